@@ -0,0 +1,61 @@
+/*
+ * CONFIG FILES
+ * Minimal INI-like format shared by all config files:
+ *
+ *   # comment
+ *   [section]
+ *   key = value
+ *
+ * Keys before the first section header belong to the "" section. Values may be wrapped in double quotes.
+ */
+#[derive(Debug)]
+pub struct Entry {
+    pub section: String,
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+pub fn parse(text: &str) -> Result<Vec<Entry>, String> {
+    let mut section = String::new();
+    let mut entries = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len()-1].trim().to_string();
+            continue;
+        }
+
+        let eq = line.find('=').ok_or_else(|| format!("line {}: expected key = value", i + 1))?;
+        let value = line[eq+1..].trim();
+        let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') { &value[1..value.len()-1] } else { value };
+        entries.push(Entry {
+            section: section.clone(),
+            key: line[..eq].trim().to_string(),
+            value: value.to_string(),
+            line: i + 1,
+        });
+    }
+    Ok(entries)
+}
+
+/*
+ * Sets key before the first section header to value, keeping every other line and comment. Replaces the first
+ * line of key there, otherwise adds one right before the first section.
+ */
+pub fn set(text: &str, key: &str, value: &str) -> String {
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    let section = lines.iter().position(|line| line.trim().starts_with('[')).unwrap_or(lines.len());
+    let entry = format!("{} = {}", key, value);
+    let found = lines[..section].iter().position(|line| {
+        line.split('=').next().map(|k| k.trim()) == Some(key) && line.contains('=') && !line.trim().starts_with('#')
+    });
+    match found {
+        Some(i) => lines[i] = entry,
+        None => lines.insert(section, entry),
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
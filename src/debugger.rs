@@ -0,0 +1,167 @@
+/*
+ * DEBUGGER
+ * Pause state, breakpoints and console commands. With --debug, commands are read from stdin:
+ *
+ *   break <addr>    add breakpoint (hex address, 0x prefix optional)
+ *   delete <addr>   remove breakpoint
+ *   continue        resume execution
+ *   pause           pause execution
+ *   step [n]        execute n instructions (default 1), stopping early at breakpoints
+ *   over            like step, but runs CALL until it returns
+ *   timers          freeze or unfreeze DT and ST, CPU keeps running
+ *   search <byte>   list addresses holding byte (hex)
+ *   again <byte>    narrow previous search to addresses now holding byte
+ *   seed <n>        restart random number generator from seed n (decimal)
+ */
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver};
+use std::{io, thread};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Break(u16),
+    Delete(u16),
+    Continue,
+    Pause,
+    Step(u32),
+    StepOver,
+    FreezeTimers,
+    Search(u8),
+    SearchAgain(u8),
+    Seed(u64),
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    pub paused: bool,
+    pub breakpoints: Vec<u16>,
+    // Removed once hit, used by step over
+    temporary: Option<u16>,
+    // Don't break on the instruction execution resumes from
+    resuming: bool,
+    console: Option<Receiver<String>>,
+    // Addresses matched by last search
+    candidates: Vec<u16>,
+}
+
+impl Debugger {
+    /*
+     * Starts reading commands from stdin on background thread.
+     */
+    pub fn with_console() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => if tx.send(line).is_err() { break; },
+                    Err(_) => break,
+                }
+            }
+        });
+        Debugger { console: Some(rx), ..Default::default() }
+    }
+
+    /*
+     * Commands typed since last call. Invalid ones are reported and skipped.
+     */
+    pub fn poll_console(&mut self) -> Vec<Command> {
+        let lines: Vec<String> = match self.console {
+            Some(ref rx) => rx.try_iter().collect(),
+            None => return Vec::new(),
+        };
+        lines.iter().filter(|l| !l.trim().is_empty()).filter_map(|line| match parse(line) {
+            Ok(cmd) => Some(cmd),
+            Err(e) => { println!("{}", e); None },
+        }).collect()
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.paused { self.resume(); } else { self.paused = true; }
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.resuming = true;
+    }
+
+    /*
+     * Runs until instruction after the current one. Caller passes address of next instruction when current one
+     * is a CALL, otherwise step over is a plain step.
+     */
+    pub fn step_over(&mut self, return_addr: u16) {
+        self.temporary = Some(return_addr);
+        self.resume();
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        if !self.breakpoints.contains(&addr) { self.breakpoints.push(addr); }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.retain(|a| *a != addr);
+    }
+
+    /*
+     * Starts new search, returning every address holding value.
+     */
+    pub fn search(&mut self, mem: &[u8], value: u8) -> &[u16] {
+        self.candidates = (0..mem.len()).filter(|a| mem[*a] == value).map(|a| a as u16).collect();
+        &self.candidates
+    }
+
+    /*
+     * Keeps candidates of previous search which hold value now.
+     */
+    pub fn search_again(&mut self, mem: &[u8], value: u8) -> &[u16] {
+        self.candidates.retain(|a| mem[*a as usize] == value);
+        &self.candidates
+    }
+
+    /*
+     * Called before each instruction. Pauses and returns true if execution should stop at pc.
+     */
+    pub fn should_break(&mut self, pc: u16) -> bool {
+        if self.resuming {
+            self.resuming = false;
+            return false;
+        }
+        let temporary = self.temporary == Some(pc);
+        if temporary || self.breakpoints.contains(&pc) {
+            if temporary { self.temporary = None; } else { println!("Breakpoint at {:#05X}", pc); }
+            self.paused = true;
+            return true;
+        }
+        false
+    }
+}
+
+pub fn parse_addr(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address {}", text))
+}
+
+pub fn parse_byte(text: &str) -> Result<u8, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u8::from_str_radix(digits, 16).map_err(|_| format!("Invalid byte {}", text))
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let arg = |i: usize| words.get(i).cloned().ok_or_else(|| format!("{} needs an argument", words[0]));
+    match words.first().cloned().unwrap_or("") {
+        "break" | "b"    => Ok(Command::Break(parse_addr(arg(1)?)?)),
+        "delete" | "d"   => Ok(Command::Delete(parse_addr(arg(1)?)?)),
+        "continue" | "c" => Ok(Command::Continue),
+        "pause" | "p"    => Ok(Command::Pause),
+        "step" | "s"     => match words.get(1) {
+            Some(n) => n.parse().map(Command::Step).map_err(|_| format!("Invalid step count {}", n)),
+            None => Ok(Command::Step(1)),
+        },
+        "over" | "n"     => Ok(Command::StepOver),
+        "timers" | "t"   => Ok(Command::FreezeTimers),
+        "search"         => Ok(Command::Search(parse_byte(arg(1)?)?)),
+        "again" | "search_again" => Ok(Command::SearchAgain(parse_byte(arg(1)?)?)),
+        "seed"           => { let n = arg(1)?; n.parse().map(Command::Seed).map_err(|_| format!("Invalid seed {}", n)) },
+        other => Err(format!("Unknown command {}", other)),
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ * DISASSEMBLER
+ * Mnemonics follow Cowgod's Chip-8 Technical Reference, numbers are hex:
+ *
+ *   200: 6A02  LD VA, 02
+ *   202: A2EA  LD I, 2EA
+ *
+ * Opcodes which can't be decoded are shown as data bytes. With --xrefs jump and call targets get labels. The
+ * address word of XO-CHIP F000 NNNN is shown with it, not as an instruction of its own.
+ */
+use std::cmp;
+use std::collections::BTreeMap;
+
+use crate::{decode, nibbles};
+
+/*
+ * Mnemonic of single opcode.
+ */
+pub fn mnemonic(op: u16, lenient: bool) -> String {
+    format_op(op, lenient, &|nnn| format!("{:03X}", nnn))
+}
+
+/*
+ * Mnemonic with jump and call targets formatted by target.
+ */
+fn format_op(op: u16, lenient: bool, target: &dyn Fn(u16) -> String) -> String {
+    let bits = nibbles(op);
+    let (_, x, y, n) = bits;
+    let (nnn, kk) = (op & 0xFFF, op & 0xFF);
+    let key = match decode(bits, lenient) {
+        Some(key) => key,
+        None => return format!("DB {:02X}, {:02X}", op >> 8, op & 0xFF),
+    };
+    match key {
+        "00E0" => String::from("CLS"),
+        "00EE" => String::from("RET"),
+        "1nnn" => format!("JP {}", target(nnn)),
+        "2nnn" => format!("CALL {}", target(nnn)),
+        "3xkk" => format!("SE V{:X}, {:02X}", x, kk),
+        "4xkk" => format!("SNE V{:X}, {:02X}", x, kk),
+        "5xy0" => format!("SE V{:X}, V{:X}", x, y),
+        "6xkk" => format!("LD V{:X}, {:02X}", x, kk),
+        "7xkk" => format!("ADD V{:X}, {:02X}", x, kk),
+        "8xy0" => format!("LD V{:X}, V{:X}", x, y),
+        "8xy1" => format!("OR V{:X}, V{:X}", x, y),
+        "8xy2" => format!("AND V{:X}, V{:X}", x, y),
+        "8xy3" => format!("XOR V{:X}, V{:X}", x, y),
+        "8xy4" => format!("ADD V{:X}, V{:X}", x, y),
+        "8xy5" => format!("SUB V{:X}, V{:X}", x, y),
+        "8xy6" => format!("SHR V{:X}, V{:X}", x, y),
+        "8xy7" => format!("SUBN V{:X}, V{:X}", x, y),
+        "8xyE" => format!("SHL V{:X}, V{:X}", x, y),
+        "9xy0" => format!("SNE V{:X}, V{:X}", x, y),
+        "Annn" => format!("LD I, {:03X}", nnn),
+        "Bnnn" => format!("JP V0, {}", target(nnn)),
+        "Cxkk" => format!("RND V{:X}, {:02X}", x, kk),
+        "Dxyn" => format!("DRW V{:X}, V{:X}, {:X}", x, y, n),
+        "Ex9E" => format!("SKP V{:X}", x),
+        "ExA1" => format!("SKNP V{:X}", x),
+        // Address is in the next word, see line
+        "F000" => String::from("LD I, LONG"),
+        "Fx07" => format!("LD V{:X}, DT", x),
+        "Fx0A" => format!("LD V{:X}, K", x),
+        "Fx15" => format!("LD DT, V{:X}", x),
+        "Fx18" => format!("LD ST, V{:X}", x),
+        "Fx1E" => format!("ADD I, V{:X}", x),
+        "Fx29" => format!("LD F, V{:X}", x),
+        "Fx33" => format!("LD B, V{:X}", x),
+        "Fx55" => format!("LD [I], V{:X}", x),
+        "Fx65" => format!("LD V{:X}, [I]", x),
+        _ => key.to_string(),
+    }
+}
+
+/*
+ * Listing of ROM loaded at entry, one aligned opcode per line.
+ */
+pub fn listing(bytes: &[u8], entry: u16, lenient: bool) -> Vec<String> {
+    let number = |nnn: u16| format!("{:03X}", nnn);
+    opcodes(bytes, entry).into_iter().map(|(addr, op, long)| line(addr, op, long, lenient, &number)).collect()
+}
+
+/*
+ * Line of listing, F000 with the address from the word after it.
+ */
+fn line(addr: u16, op: u16, long: Option<u16>, lenient: bool, target: &dyn Fn(u16) -> String) -> String {
+    let text = match long {
+        Some(nnnn) => format!("LD I, {:04X}", nnnn),
+        None => format_op(op, lenient, target),
+    };
+    format!("{:03X}: {:04X}  {}", addr, op, text)
+}
+
+/*
+ * Listing with labels. First pass collects 1nnn, 2nnn and Bnnn targets, every target which starts a line gets
+ * a label, listing the addresses referencing it:
+ *
+ *   L_0300:  ; 204 2A0
+ *   300: 6A02  LD VA, 02
+ *
+ * Jumps to labeled addresses show the label instead of the number.
+ */
+pub fn xref_listing(bytes: &[u8], entry: u16, lenient: bool) -> Vec<String> {
+    let mut refs: BTreeMap<u16, Vec<u16>> = BTreeMap::new();
+    for (addr, op, _) in opcodes(bytes, entry) {
+        match decode(nibbles(op), lenient) {
+            Some("1nnn") | Some("2nnn") | Some("Bnnn") => refs.entry(op & 0xFFF).or_default().push(addr),
+            _ => {}
+        }
+    }
+    // Targets inside data or between aligned opcodes can't be labeled
+    let starts: Vec<u16> = opcodes(bytes, entry).into_iter().map(|(addr, _, _)| addr).collect();
+    refs.retain(|target, _| starts.binary_search(target).is_ok());
+
+    let label = |nnn: u16| if refs.contains_key(&nnn) { format!("L_{:04X}", nnn) } else { format!("{:03X}", nnn) };
+    let mut lines = Vec::new();
+    for (addr, op, long) in opcodes(bytes, entry) {
+        if let Some(from) = refs.get(&addr) {
+            let from: Vec<String> = from.iter().map(|a| format!("{:03X}", a)).collect();
+            lines.push(format!("L_{:04X}:  ; {}", addr, from.join(" ")));
+        }
+        lines.push(line(addr, op, long, lenient, &label));
+    }
+    lines
+}
+
+/*
+ * Listing of live memory around pc, lines before it and after it, in steps of two bytes from pc. Current
+ * instruction is marked with >, opcodes the ROM wrote itself with *.
+ */
+pub fn around_pc(mem: &[u8], written: &[bool], pc: u16, lines: (usize, usize), lenient: bool) -> Vec<String> {
+    let (before, after) = lines;
+    let start = pc.saturating_sub(2 * before as u16) as usize;
+    let end = cmp::min(pc as usize + 2 * (after + 1), mem.len());
+    if start >= end { return Vec::new(); }
+    let number = |nnn: u16| format!("{:03X}", nnn);
+    opcodes(&mem[start..end], start as u16).into_iter().map(|(addr, op, long)| {
+        let at = addr as usize;
+        let cursor = if addr == pc { '>' } else { ' ' };
+        let modified = if written[at] || written.get(at + 1) == Some(&true) { '*' } else { ' ' };
+        format!("{}{}{}", cursor, modified, line(addr, op, long, lenient, &number))
+    }).collect()
+}
+
+/*
+ * Address and opcode of every aligned pair, odd last byte padded with zero. F000 takes the word after it as its
+ * address, which is skipped.
+ */
+pub fn opcodes(bytes: &[u8], entry: u16) -> Vec<(u16, u16, Option<u16>)> {
+    let word = |i: usize| ((bytes[i] as u16) << 8) | *bytes.get(i + 1).unwrap_or(&0) as u16;
+    let mut ops = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let op = word(i);
+        let long = if op == 0xF000 && i + 2 < bytes.len() { Some(word(i + 2)) } else { None };
+        ops.push((entry + i as u16, op, long));
+        i += if long.is_some() { 4 } else { 2 };
+    }
+    ops
+}
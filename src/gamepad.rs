@@ -0,0 +1,73 @@
+/*
+ * GAMEPADS
+ * Controllers connected when the window opens drive the keypad alongside the keyboard. Devices found in SDL's game
+ * controller database, built in or extended with --controller-db (gamecontrollerdb.txt format), get the same
+ * layout whatever their make: d-pad on 2/4/6/8, the usual action keys on the face buttons. Joysticks without
+ * a mapping fall back to their first hat and buttons in order.
+ */
+use sdl2::controller::{Button, GameController};
+use sdl2::joystick::{HatState, Joystick};
+
+const CONTROLLER_KEYS: [(Button, usize); 12] = [
+    (Button::DPadUp, 0x2), (Button::DPadDown, 0x8), (Button::DPadLeft, 0x4), (Button::DPadRight, 0x6),
+    (Button::A, 0x5), (Button::B, 0x0), (Button::X, 0xA), (Button::Y, 0xB),
+    (Button::LeftShoulder, 0x1), (Button::RightShoulder, 0x3), (Button::Back, 0xE), (Button::Start, 0xF),
+];
+
+// Keypad values of buttons 0, 1, 2... of unmapped joysticks
+const GENERIC_BUTTONS: [usize; 8] = [0x5, 0x0, 0xA, 0xB, 0x1, 0x3, 0xE, 0xF];
+
+pub struct Gamepads {
+    controllers: Vec<GameController>,
+    joysticks: Vec<Joystick>,
+}
+
+impl Gamepads {
+    /*
+     * Opens every connected device, loading extra mappings from db first.
+     */
+    pub fn open(video: &sdl2::VideoSubsystem, db: Option<&str>) -> Result<Self, String> {
+        let sdl = video.sdl();
+        let subsystem = sdl.game_controller()?;
+        let joystick_subsystem = sdl.joystick()?;
+        if let Some(path) = db {
+            let count = subsystem.load_mappings(path).map_err(|e| format!("Unable to load controller database {}: {}", path, e))?;
+            println!("Loaded {} controller mappings from {}", count, path);
+        }
+
+        let mut pads = Gamepads { controllers: Vec::new(), joysticks: Vec::new() };
+        for index in 0..subsystem.num_joysticks()? {
+            if subsystem.is_game_controller(index) {
+                let controller = subsystem.open(index).map_err(|e| e.to_string())?;
+                println!("Controller: {}", controller.name());
+                pads.controllers.push(controller);
+            } else {
+                let joystick = joystick_subsystem.open(index).map_err(|e| e.to_string())?;
+                println!("Joystick without mapping, using generic layout: {}", joystick.name());
+                pads.joysticks.push(joystick);
+            }
+        }
+        Ok(pads)
+    }
+
+    /*
+     * Keypad values held on any device.
+     */
+    pub fn held(&self) -> Vec<usize> {
+        let mut keys = Vec::new();
+        for controller in &self.controllers {
+            keys.extend(CONTROLLER_KEYS.iter().filter(|(button, _)| controller.button(*button)).map(|(_, key)| *key));
+        }
+        for joystick in &self.joysticks {
+            if joystick.num_hats() > 0 {
+                let hat = joystick.hat(0).unwrap_or(HatState::Centered) as u8;
+                let directions = [(HatState::Up, 0x2), (HatState::Down, 0x8), (HatState::Left, 0x4), (HatState::Right, 0x6)];
+                keys.extend(directions.iter().filter(|(dir, _)| hat & *dir as u8 != 0).map(|(_, key)| *key));
+            }
+            for (button, key) in GENERIC_BUTTONS.iter().enumerate().take(joystick.num_buttons() as usize) {
+                if joystick.button(button as u32).unwrap_or(false) { keys.push(*key); }
+            }
+        }
+        keys
+    }
+}
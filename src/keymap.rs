@@ -0,0 +1,163 @@
+/*
+ * KEYMAP
+ * Physical keys for the 16-key keypad and for emulator controls. Loaded from file given with --keymap:
+ *
+ *   # hex keypad value = SDL key name
+ *   [keypad]
+ *   0 = X
+ *   1 = 1
+ *   ...
+ *   # control name = SDL key name
+ *   [controls]
+ *   pause = F5
+ *
+ * Entries which are left out keep their defaults.
+ */
+use std::fs;
+use sdl2::keyboard::Keycode;
+
+use crate::config;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    Quit,
+    ScaleUp,
+    ScaleDown,
+    Theme,
+    Invert,
+    Profile,
+    DumpMemory,
+    DumpState,
+    Pause,
+    Step,
+    StepOver,
+    Reset,
+    ClearDisplay,
+    // Shown while held, not toggled
+    DebugOverlay,
+    Disassemble,
+}
+
+const CONTROL_NAMES: [(&str, Control); 15] = [
+    ("quit",          Control::Quit),
+    ("scale_up",      Control::ScaleUp),
+    ("scale_down",    Control::ScaleDown),
+    ("theme",         Control::Theme),
+    ("invert",        Control::Invert),
+    ("profile",       Control::Profile),
+    ("dump_memory",   Control::DumpMemory),
+    ("dump_state",    Control::DumpState),
+    ("pause",         Control::Pause),
+    ("step",          Control::Step),
+    ("step_over",     Control::StepOver),
+    ("reset",         Control::Reset),
+    ("clear_display", Control::ClearDisplay),
+    ("debug_overlay", Control::DebugOverlay),
+    ("disassemble",   Control::Disassemble),
+];
+
+pub struct Keymap {
+    // Indexed by keypad value
+    pub keypad: [Keycode; 16],
+    pub controls: Vec<(Control, Keycode)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            keypad: [
+                Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
+                Keycode::Q, Keycode::W, Keycode::E, Keycode::R,
+                Keycode::A, Keycode::S, Keycode::D, Keycode::F,
+                Keycode::Z, Keycode::X, Keycode::C, Keycode::V,
+            ],
+            controls: vec![
+                (Control::Quit,         Keycode::Escape),
+                (Control::ScaleUp,      Keycode::Equals),
+                (Control::ScaleDown,    Keycode::Minus),
+                (Control::Theme,        Keycode::F1),
+                (Control::Invert,       Keycode::F2),
+                (Control::DebugOverlay, Keycode::F3),
+                (Control::Profile,      Keycode::F4),
+                (Control::Pause,        Keycode::F5),
+                (Control::Step,         Keycode::F6),
+                (Control::Reset,        Keycode::F7),
+                (Control::DumpMemory,   Keycode::F8),
+                (Control::StepOver,     Keycode::F9),
+                (Control::DumpState,    Keycode::F10),
+                (Control::ClearDisplay, Keycode::F11),
+                (Control::Disassemble,  Keycode::F12),
+            ],
+        }
+    }
+}
+
+impl Keymap {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read keymap {}: {}", path, e))?;
+        let mut keymap = Keymap::default();
+
+        for entry in config::parse(&text).map_err(|e| format!("{}: {}", path, e))? {
+            let key = Keycode::from_name(&entry.value)
+                .ok_or_else(|| format!("{}:{}: unknown key {}", path, entry.line, entry.value))?;
+            match entry.section.as_str() {
+                "keypad" => {
+                    let idx = usize::from_str_radix(&entry.key, 16).ok().filter(|i| *i < 16)
+                        .ok_or_else(|| format!("{}:{}: keypad value must be 0-F", path, entry.line))?;
+                    keymap.keypad[idx] = key;
+                },
+                "controls" => {
+                    let (_, control) = CONTROL_NAMES.iter().find(|(name, _)| *name == entry.key)
+                        .ok_or_else(|| format!("{}:{}: unknown control {}", path, entry.line, entry.key))?;
+                    keymap.controls.retain(|(c, _)| c != control);
+                    keymap.controls.push((*control, key));
+                },
+                _ => return Err(format!("{}:{}: unknown section [{}]", path, entry.line, entry.section)),
+            }
+        }
+        Ok(keymap)
+    }
+
+    pub fn control(&self, key: Keycode) -> Option<Control> {
+        self.controls.iter().find(|(_, k)| *k == key).map(|(c, _)| *c)
+    }
+
+    pub fn keypad_value(&self, key: Keycode) -> Option<usize> {
+        self.keypad.iter().position(|k| *k == key)
+    }
+
+    /*
+     * Human readable table of bindings, keypad in keypad layout order.
+     */
+    pub fn describe(&self) -> String {
+        let mut out = String::from("Keypad:\n");
+        for value in [0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF].iter() {
+            out += &format!("  {:<12} -> {:X}\n", self.keypad[*value].name(), value);
+        }
+        out += "Controls:\n";
+        for (control, key) in &self.controls {
+            out += &format!("  {:<12} -> {}\n", key.name(), control_name(*control));
+        }
+        out
+    }
+
+    /*
+     * Describes every key bound twice, either to a control and the keypad or to two controls.
+     */
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut found = Vec::new();
+        for (i, (control, key)) in self.controls.iter().enumerate() {
+            if let Some(value) = self.keypad_value(*key) {
+                found.push(format!("{} is bound to both {} and keypad {:X}", key.name(), control_name(*control), value));
+            }
+            for (other, _) in self.controls[i+1..].iter().filter(|(_, k)| k == key) {
+                found.push(format!("{} is bound to both {} and {}", key.name(), control_name(*control), control_name(*other)));
+            }
+        }
+        found
+    }
+}
+
+pub fn control_name(control: Control) -> &'static str {
+    CONTROL_NAMES.iter().find(|(_, c)| *c == control).map(|(name, _)| *name).unwrap()
+}
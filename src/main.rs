@@ -1,36 +1,122 @@
+mod config;
+mod debugger;
+mod disasm;
+mod font;
+mod gamepad;
+mod keymap;
+mod options;
+mod png;
+mod quirks;
+mod replay;
+mod savestate;
+mod script;
+mod sha256;
+mod trace;
+
 use std::default::Default;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::num::Wrapping;
-use std::time::Duration;
-use std::{fs, env, path, thread};
+use std::fmt;
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{cmp, fs, env, hint, io, panic, path, thread};
 use rodio::{Sink, Source};
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde_json::json;
 
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::rect::Rect;
 
+use debugger::{Command, Debugger};
+use gamepad::Gamepads;
+use keymap::{Control, Keymap};
+use options::{ExitFrom, Options, Subcommand};
+use quirks::Quirks;
+use replay::{Recorder, Replay, Settings};
+use script::InputScript;
+use trace::Tracer;
+
 /*
  * TYPE ALIASES & CONSTS
  */
-type Mem = [u8; RAM_SIZE];
-type Stack = [u16; STACK_SIZE];
+type Mem = Vec<u8>;
+type Stack = Vec<u16>;
 type Keyboard = [bool; KEYBOARD_SIZE];
 type Op = u16;
 type ChunkedOp = (usize, usize, usize, usize);
 
 const GP_REG_CNT: usize = 16;
 const KEYBOARD_SIZE: usize = 16;
+// Default memory size, XO-CHIP addresses up to MAX_RAM_SIZE
 const RAM_SIZE: usize = 0x1000;
+const MAX_RAM_SIZE: usize = 0x10000;
+// COSMAC VIP display memory, 64x32 pixels packed 8 per byte
+const VIP_DISPLAY_START: usize = 0xF00;
+const VIP_DISPLAY_END: usize = 0x1000;
 const STACK_SIZE: usize = 16;
+const MAX_STACK_SIZE: usize = 255;
+const INSTRUCTIONS_PER_FRAME: usize = 9;
+// COSMAC VIP machine cycles per 60Hz frame (1.76MHz clock, 8 clocks per machine cycle)
+const FRAME_CYCLES: u32 = 3668;
+// Instructions executed between input polls with --max-speed
+const MAX_SPEED_BATCH: usize = 1000;
+// Longest sleep while idle, input wakes it earlier
+const IDLE_WAIT_MS: u32 = 50;
+// How long on-screen messages stay visible
+const TOAST_DURATION: Duration = Duration::from_secs(2);
+// Default length of bench command, one minute of emulated time
+const BENCH_FRAMES: u64 = 3600;
+// Executions of every opcode timed by bench --opcodes
+const OPCODE_BENCH_RUNS: u32 = 100000;
+// One opcode per instruction for bench --opcodes, run at 0x300 with I at 0x400
+const BENCH_OPCODES: [u16; 36] = [
+    0x00E0, 0x00EE, 0x1300, 0x2300, 0x3000, 0x4000, 0x5010, 0x6012, 0x7001, 0x8010, 0x8011, 0x8012, 0x8013,
+    0x8014, 0x8015, 0x8016, 0x8017, 0x801E, 0x9010, 0xA400, 0xB300, 0xC0FF, 0xD015, 0xE09E, 0xE0A1, 0xF000,
+    0xF007, 0xF00A, 0xF015, 0xF018, 0xF01E, 0xF029, 0xF033, 0xFF55, 0xFF65, 0xD01F,
+];
+// Frames kept by --show-stats graph
+const STATS_FRAMES: usize = 120;
+// Limit of frames run by --fast-boot, a minute
+const FAST_BOOT_FRAMES: u64 = 3600;
+// Length of --dt-tick click
+const TICK_DURATION: Duration = Duration::from_millis(4);
+// Shortest start delay with --splash, one second
+const SPLASH_FRAMES: u64 = 60;
+// Memory map of debug overlay, cells per row and size of a cell in pixels including its 1px gap
+const MEMORY_MAP_CELLS: usize = 256;
+const MEMORY_MAP_COLUMNS: usize = 64;
+const MEMORY_MAP_CELL: u32 = 5;
 
 const DISPLAY_MODE_WIDTH: usize = 64;
 const DISPLAY_MODE_HEIGHT: usize = 32;
-const DISPLAY_SCALED_WIDTH: usize = DISPLAY_MODE_WIDTH * 10;
-const DISPLAY_SCALED_HEIGHT: usize = DISPLAY_MODE_HEIGHT * 10;
+const MAX_SCALE: u32 = 20;
 const ENTRY_POINT: u16 = 0x200;
 
+// Hex digits sprites, loaded at the start of memory
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
 /*
  * REGISTERS
  */
@@ -59,6 +145,7 @@ pub struct Reg {
     pub PC: u16,
     /*
      * Stack Pointer
+     * Number of return addresses on the stack.
      */
     pub SP: u8,
 }
@@ -71,202 +158,1365 @@ impl Reg {
         if self.DT > 0 { self.DT -= 1; }
     }
 
-    pub fn update_ST(&mut self, audio: &Audio) {
-        if self.ST > 0 {
-            if !audio.is_playing() { audio.play(); }
-            self.ST -= 1;
-            if self.ST == 0 { audio.stop(); }
-        }
+    pub fn update_ST(&mut self) {
+        if self.ST > 0 { self.ST -= 1; }
     }
 }
+/*
+ * What frontend should play, see State::audio_events. The VM doesn't produce any sound itself.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioEvent {
+    // ST became nonzero or ran out
+    Beep { on: bool },
+    // DT decremented
+    Tick,
+}
 /*
  * AUDIO
+ * rodio frontend for AudioEvent. Tone plays all the time, gated by an envelope. Ramping volume instead of
+ * pausing the sink avoids clicks.
  */
 pub struct Audio {
     player: rodio::Sink,
+    gate: Arc<AtomicBool>,
+    // Second sink for --dt-tick clicks
+    ticker: Option<rodio::Sink>,
 }
 impl Audio {
-    pub fn new() -> Self {
+    /*
+     * Plays looped WAV sample instead of the sine if given one, falling back to sine when it can't be loaded.
+     * Pan goes from -1(left) to 1(right), 0 keeps source channels as they are.
+     */
+    pub fn new(ramp: Duration, wav: Option<&str>, pan: f32) -> Self {
         let device = rodio::default_output_device().unwrap();
         let sink = Sink::new(&device);
+        let gate = Arc::new(AtomicBool::new(false));
 
         sink.set_volume(0.75);
-        Audio { player: sink }
+        let sample = wav.and_then(|path| Audio::load_wav(path)
+            .map_err(|e| eprintln!("Unable to load {}: {}, using default beep", path, e)).ok());
+        match sample {
+            Some(sample) => Audio::append(&sink, Envelope::new(sample, gate.clone(), ramp), pan),
+            None => Audio::append(&sink, Envelope::new(rodio::source::SineWave::new(300), gate.clone(), ramp), pan),
+        }
+        Audio { player: sink, gate: gate, ticker: None }
     }
 
-    pub fn play(&self) {
-        if self.player.len() == 0 {
-            let source = rodio::source::SineWave::new(300).repeat_infinite();
-            self.player.append(source);
+    /*
+     * Panned source is mixed down to mono and played on left and right channel at their volumes.
+     */
+    fn append<S: Source<Item = f32> + Send + 'static>(sink: &Sink, source: S, pan: f32) {
+        if pan == 0.0 {
+            sink.append(source);
+        } else {
+            let (left, right) = ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0));
+            sink.append(rodio::source::ChannelVolume::new(source, vec![left, right]));
+        }
+    }
+
+    fn load_wav(path: &str) -> Result<impl Source<Item = f32>, String> {
+        let file = fs::File::open(path).map_err(|e| e.to_string())?;
+        let decoder = rodio::Decoder::new(io::BufReader::new(file)).map_err(|e| e.to_string())?;
+        Ok(decoder.convert_samples::<f32>().buffered().repeat_infinite())
+    }
+
+    /*
+     * Clicks on every DT decrement from their own sink, so they don't interfere with the beep.
+     */
+    pub fn enable_tick(&mut self) {
+        let device = rodio::default_output_device().unwrap();
+        self.ticker = Some(Sink::new(&device));
+    }
+
+    pub fn tick(&self) {
+        if let Some(ref ticker) = self.ticker {
+            ticker.append(rodio::source::SineWave::new(1500).take_duration(TICK_DURATION).amplify(0.4));
         }
-        self.player.play();
+    }
+
+    pub fn play(&self) {
+        self.gate.store(true, Ordering::Relaxed);
     }
 
     pub fn stop(&self) {
-        self.player.pause();
+        self.gate.store(false, Ordering::Relaxed);
+    }
+
+    /*
+     * Drops queued tone, so no beep outlives the window.
+     */
+    pub fn shutdown(&self) {
+        self.player.stop();
+        if let Some(ref ticker) = self.ticker { ticker.stop(); }
+    }
+}
+
+/*
+ * Scales samples by level moving linearly towards 1 while gate is open and towards 0 while closed.
+ */
+struct Envelope<S> {
+    source: S,
+    gate: Arc<AtomicBool>,
+    level: f32,
+    // Level change per sample
+    step: f32,
+}
+impl<S: Source<Item = f32>> Envelope<S> {
+    fn new(source: S, gate: Arc<AtomicBool>, ramp: Duration) -> Self {
+        let samples = ramp.as_secs_f32() * source.sample_rate() as f32 * source.channels() as f32;
+        let step = if samples < 1.0 { 1.0 } else { 1.0 / samples };
+        Envelope { source: source, gate: gate, level: 0.0, step: step }
+    }
+}
+impl<S: Source<Item = f32>> Iterator for Envelope<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.gate.load(Ordering::Relaxed) {
+            self.level = (self.level + self.step).min(1.0);
+        } else {
+            self.level = (self.level - self.step).max(0.0);
+        }
+        self.source.next().map(|sample| sample * self.level)
+    }
+}
+impl<S: Source<Item = f32>> Source for Envelope<S> {
+    fn current_frame_len(&self) -> Option<usize> { self.source.current_frame_len() }
+    fn channels(&self) -> u16 { self.source.channels() }
+    fn sample_rate(&self) -> u32 { self.source.sample_rate() }
+    fn total_duration(&self) -> Option<Duration> { None }
+}
+/*
+ * How Dxyn combines sprites with the display. Or never erases, for experiments and debugging.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrawMode {
+    Xor,
+    Or,
+}
+impl DrawMode {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "xor" => Ok(DrawMode::Xor),
+            "or" => Ok(DrawMode::Or),
+            _ => Err(format!("Unknown draw mode {}", name)),
+        }
     }
 
-    pub fn is_playing(&self) -> bool {
-        !self.player.is_paused() && self.player.len() > 0
+    pub fn name(self) -> &'static str {
+        match self {
+            DrawMode::Xor => "xor",
+            DrawMode::Or => "or",
+        }
     }
 }
 /*
  * DISPLAY
  * Contains sprite drawing logic and image scaling.
  */
+#[derive(Clone)]
 pub struct Display {
     pub width: usize,
     pub height: usize,
     pub readraw: bool,
-    buffer: Vec<Vec<bool>>,
+    // Render frames a cleared pixel stays visible for, hides XOR flicker
+    pub persist: u8,
+    // Dxyn draws one intensity byte per pixel instead of one bit, see blend
+    pub grayscale: bool,
+    // Show pixels lit at any point of the current 60Hz tick, see tick
+    pub smooth: bool,
+    // Sprite pixels XORed or ORed in by pixel and draw
+    pub draw_mode: DrawMode,
+    // Track where wrapped sprite pixels came from, see wrapped_from
+    pub show_wrap: bool,
+    // Intensity of every pixel, 0 or 0xFF unless grayscale
+    buffer: Vec<Vec<u8>>,
+    // Remaining render frames of cleared pixels, see lit and age
+    fade: Vec<Vec<u8>>,
+    // Pixels cleared during current tick, shown when smooth
+    erased: Vec<Vec<bool>>,
+    // Unwrapped coordinates of sprite pixel which last touched cell, if it had to wrap
+    wraps_from: Vec<Vec<Option<(usize, usize)>>>,
 }
 
 impl Display {
     pub fn new(width: usize, height: usize) -> Self {
         Display { 
             width: width, height: height,
-            buffer: vec![vec![false; width]; height],
+            buffer: vec![vec![0; width]; height],
+            fade: vec![vec![0; width]; height],
+            erased: vec![vec![false; width]; height],
+            wraps_from: vec![vec![None; width]; height],
             readraw: true,
+            persist: 0,
+            grayscale: false,
+            smooth: false,
+            draw_mode: DrawMode::Xor,
+            show_wrap: false,
         }
     }
 
     pub fn cls(&mut self) { 
-        self.buffer = vec![vec![false; self.width]; self.height]; 
+        for ((row, fade), erased) in self.buffer.iter().zip(self.fade.iter_mut()).zip(self.erased.iter_mut()) {
+            for ((level, left), gone) in row.iter().zip(fade.iter_mut()).zip(erased.iter_mut()) {
+                if *level != 0 { *left = self.persist; *gone |= self.smooth; }
+            }
+        }
+        self.buffer = vec![vec![0; self.width]; self.height]; 
+        self.wraps_from = vec![vec![None; self.width]; self.height];
         self.readraw = true;
     }
 
     pub fn pixel(&mut self, row: usize, col: usize, update: bool) -> bool {
+        self.draw(row, col, if update { 0xFF } else { 0 })
+    }
+
+    /*
+     * Blends sprite intensity into pixel as set by draw_mode. ORed pixels never collide, only bits which aren't
+     * lit yet change.
+     */
+    pub fn draw(&mut self, row: usize, col: usize, value: u8) -> bool {
+        match self.draw_mode {
+            DrawMode::Xor => self.blend(row, col, value),
+            DrawMode::Or => {
+                let current = self.buffer[row % self.height][col % self.width];
+                self.blend(row, col, value & !current);
+                false
+            },
+        }
+    }
+
+    /*
+     * Whether coordinates are past the edge, so pixel and blend wrap them around.
+     */
+    pub fn wraps(&self, row: usize, col: usize) -> bool {
+        row >= self.height || col >= self.width
+    }
+
+    /*
+     * XORs intensity into pixel. Returns true if pixel was lit and got touched, same as collision of pixel.
+     */
+    pub fn blend(&mut self, row: usize, col: usize, value: u8) -> bool {
+        let from = if self.wraps(row, col) { Some((row, col)) } else { None };
         let row = row % self.height;
         let col = col % self.width;        
-        let overriden = self.buffer[row][col] && update;
+        if self.show_wrap && value != 0 { self.wraps_from[row][col] = from; }
+        let overriden = self.buffer[row][col] != 0 && value != 0;
 
-        self.buffer[row][col] ^= update;
-        self.readraw |= update;
+        self.buffer[row][col] ^= value;
+        self.readraw |= value != 0;
+        if overriden && self.buffer[row][col] == 0 {
+            self.fade[row][col] = self.persist;
+            self.erased[row][col] |= self.smooth;
+        }
         overriden
     }
+
+    /*
+     * Whether pixel should be drawn, either lit or cleared less than persist render frames ago.
+     */
+    pub fn lit(&self, row: usize, col: usize) -> bool {
+        self.buffer[row][col] != 0 || self.fade[row][col] > 0 || self.erased[row][col]
+    }
+
+    /*
+     * SVG image with one unit per pixel, shown at scale: background rect and one rect per lit pixel.
+     */
+    pub fn to_svg(&self, scale: u32, fg: Color, bg: Color) -> String {
+        let hex = |c: Color| format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b);
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" shape-rendering=\"crispEdges\">\n",
+            self.width as u32 * scale, self.height as u32 * scale, self.width, self.height);
+        svg += &format!("  <rect width=\"{}\" height=\"{}\" fill=\"{}\"/>\n", self.width, self.height, hex(bg));
+        for i in 0..self.height {
+            for j in 0..self.width {
+                if self.buffer[i][j] != 0 {
+                    let color = hex(shade(fg, bg, self.buffer[i][j]));
+                    svg += &format!("  <rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\" fill=\"{}\"/>\n", j, i, color);
+                }
+            }
+        }
+        svg + "</svg>\n"
+    }
+
+    /*
+     * Drawn intensity of pixel, fading pixels being fully lit.
+     */
+    pub fn intensity(&self, row: usize, col: usize) -> u8 {
+        if self.fade[row][col] > 0 || self.erased[row][col] { 0xFF } else { self.buffer[row][col] }
+    }
+
+    /*
+     * Columns and rows rendered. With show_wrap there's room for the 7 columns and 14 rows a sprite reaches past
+     * the edge.
+     */
+    pub fn area(&self) -> (usize, usize) {
+        if self.show_wrap { (self.width + 7, self.height + 14) } else { (self.width, self.height) }
+    }
+
+    /*
+     * Unwrapped coordinates, past the right or bottom edge, of the sprite pixel which lit cell by wrapping.
+     */
+    pub fn wrapped_from(&self, row: usize, col: usize) -> Option<(usize, usize)> {
+        if self.buffer[row][col] != 0 { self.wraps_from[row][col] } else { None }
+    }
+
+    /*
+     * Called at the start of every 60Hz tick. Pixels erased in previous tick are dropped, so a sprite erased and
+     * redrawn across ticks never blinks.
+     */
+    pub fn tick(&mut self) {
+        if !self.smooth { return; }
+        self.readraw |= self.erased.iter().flatten().any(|gone| *gone);
+        self.erased = vec![vec![false; self.width]; self.height];
+    }
+
+    /*
+     * Called after every rendered frame, counts down cleared pixels. Keeps redrawing until all of them are gone.
+     */
+    pub fn age(&mut self) {
+        let mut changed = false;
+        for left in self.fade.iter_mut().flatten().filter(|left| **left > 0) {
+            *left -= 1;
+            changed = true;
+        }
+        self.readraw |= changed;
+    }
+
+    /*
+     * Lit pixels packed 8 per byte, row-major, highest bit being leftmost. Intensity is lost in grayscale mode.
+     */
+    pub fn packed_bits(&self) -> Vec<u8> {
+        let pixels: Vec<bool> = self.buffer.iter().flatten().map(|p| *p != 0).collect();
+        pixels.chunks(8)
+            .map(|chunk| chunk.iter().enumerate().fold(0, |byte, (i, lit)| byte | ((*lit as u8) << (7 - i))))
+            .collect()
+    }
+
+    /*
+     * Sets 8 pixels of byte at index of packed_bits layout.
+     */
+    pub fn set_packed_byte(&mut self, index: usize, byte: u8) {
+        for i in 0..8 {
+            let (row, col) = ((index * 8 + i) / self.width, (index * 8 + i) % self.width);
+            if row >= self.height { return; }
+            let value = if byte & (0x80 >> i) != 0 { 0xFF } else { 0 };
+            let changed = self.buffer[row][col] ^ value;
+            self.blend(row, col, changed);
+        }
+    }
+
+    /*
+     * Display with pixels lit as given by packed_bits.
+     */
+    pub fn from_packed_bits(width: usize, height: usize, bits: &[u8]) -> Result<Self, String> {
+        if bits.len() != (width * height).div_ceil(8) {
+            return Err(format!("{}x{} display needs {} packed bytes, got {}", width, height, (width * height).div_ceil(8), bits.len()));
+        }
+        let mut display = Display::new(width, height);
+        for i in 0..width * height {
+            if bits[i / 8] & (0x80 >> (i % 8)) != 0 { display.buffer[i / width][i % width] = 0xFF; }
+        }
+        Ok(display)
+    }
+
+    /*
+     * Parses frame in the format written by to_string, one row per line, '#' for lit pixels and '.' for unlit.
+     * Hex string of packed_bits, like "display" of to_json, is accepted too.
+     */
+    pub fn parse_frame(&self, text: &str) -> Result<Vec<Vec<bool>>, String> {
+        let hex = text.trim();
+        if hex.len() == (self.width * self.height).div_ceil(8) * 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            let bits: Vec<u8> = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i+2], 16).unwrap()).collect();
+            let frame = Display::from_packed_bits(self.width, self.height, &bits)?;
+            return Ok(frame.buffer.iter().map(|row| row.iter().map(|p| *p != 0).collect()).collect());
+        }
+        let rows: Vec<Vec<bool>> = text.lines().filter(|l| !l.trim().is_empty())
+            .map(|l| l.trim().chars().map(|c| c == '#').collect())
+            .collect();
+        if rows.len() != self.height || rows.iter().any(|r| r.len() != self.width) {
+            return Err(format!("Frame must be {}x{} pixels", self.width, self.height));
+        }
+        Ok(rows)
+    }
+
+    /*
+     * Returns true if pixel differs from the expected frame.
+     */
+    pub fn differs(&self, expected: &[Vec<bool>], row: usize, col: usize) -> bool {
+        expected[row][col] != (self.buffer[row][col] != 0)
+    }
+
+    /*
+     * Display as RGBA image, 4 bytes per pixel and rows packed without padding. Every cell becomes
+     * scale x scale block of pixels.
+     */
+    pub fn rgba_buffer(&self, scale: usize, fg: Color, bg: Color) -> Vec<u8> {
+        let width = self.width * scale;
+        let mut image = Vec::with_capacity(width * self.height * scale * 4);
+        for i in 0..self.height {
+            let mut line = Vec::with_capacity(width * 4);
+            for j in 0..self.width {
+                let c = shade(fg, bg, self.intensity(i, j));
+                line.extend([c.r, c.g, c.b, c.a].iter().cycle().take(4 * scale));
+            }
+            for _ in 0..scale { image.extend_from_slice(&line); }
+        }
+        image
+    }
+}
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in &self.buffer {
+            let line: String = row.iter().map(|p| if *p != 0 { '#' } else { '.' }).collect();
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
 }
 /*
- * STATE
- * Contains all vartiables needed for executions(regs, memory, dispaly, etc.)
+ * THEMES
+ * Built-in foreground/background color pairs.
  */
-pub struct State {
-    pub mem: Mem,
-    pub stack: Stack,
-    pub reg: Reg,
-    pub display: Display,
-    pub audio: Audio,
-    pub key: Keyboard,
+pub struct Theme {
+    pub name: &'static str,
+    pub fg: Color,
+    pub bg: Color,
+}
 
-    // Index of register to put key idx into
-    pub awaiting_input: u8,
+const THEMES: [Theme; 4] = [
+    Theme { name: "white", fg: Color { r: 255, g: 255, b: 255, a: 255 }, bg: Color { r: 0, g: 0, b: 0, a: 255 } },
+    Theme { name: "amber", fg: Color { r: 255, g: 176, b: 0, a: 255 },   bg: Color { r: 0, g: 0, b: 0, a: 255 } },
+    Theme { name: "green", fg: Color { r: 51, g: 255, b: 51, a: 255 },   bg: Color { r: 0, g: 0, b: 0, a: 255 } },
+    Theme { name: "blue",  fg: Color { r: 80, g: 160, b: 255, a: 255 },  bg: Color { r: 0, g: 0, b: 0, a: 255 } },
+];
+/*
+ * Color given as RRGGBB hex, with or without leading #.
+ */
+pub fn parse_color(text: &str) -> Result<Color, String> {
+    let hex = text.trim_start_matches('#');
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok(Color::RGB(r, g, b)),
+        _ => Err(format!("Invalid color {}, expected RRGGBB hex", text)),
+    }
 }
 /*
- * INSTRUCTIONS
+ * Color between background(level 0) and foreground(level 0xFF).
  */
-pub struct Inst {
-    instructions: HashMap<&'static str, Box<FnMut(ChunkedOp, &mut State)>>,
+pub fn shade(fg: Color, bg: Color, level: u8) -> Color {
+    let mix = |f: u8, b: u8| (b as i32 + (f as i32 - b as i32) * level as i32 / 255) as u8;
+    Color::RGB(mix(fg.r, bg.r), mix(fg.g, bg.g), mix(fg.b, bg.b))
 }
-impl Inst{
+/*
+ * RENDER TRANSFORM
+ * Maps display cells onto window pixels. Cells are integer sized and the image is centered in the window.
+ * With show_wrap the centered area extends past the right and bottom edge, see Display::area.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    pub cell: u32,
+    pub x: i32,
+    pub y: i32,
+}
+impl Transform {
+    pub fn new((width, height): (u32, u32), display: &Display) -> Self {
+        let (cols, rows) = display.area();
+        let cell = cmp::max(1, cmp::min(width / cols as u32, height / rows as u32));
+        Transform {
+            cell: cell,
+            x: (width as i32 - (cell * cols as u32) as i32) / 2,
+            y: (height as i32 - (cell * rows as u32) as i32) / 2,
+        }
+    }
+
+    pub fn rect(&self, row: usize, col: usize) -> Rect {
+        let x = self.x + (col as u32 * self.cell) as i32;
+        let y = self.y + (row as u32 * self.cell) as i32;
+        Rect::new(x, y, self.cell, self.cell)
+    }
+}
+/*
+ * PIXEL SHAPE
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelShape {
+    Square,
+    // Dot matrix look
+    Circle,
+}
+impl PixelShape {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "square" => Ok(PixelShape::Square),
+            "circle" => Ok(PixelShape::Circle),
+            _ => Err(format!("Unknown pixel shape {}", name)),
+        }
+    }
+
+    /*
+     * Fills cell with current draw color. Circle is drawn as one horizontal span per pixel row.
+     */
+    pub fn fill(self, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, cell: Rect) -> Result<(), String> {
+        if self == PixelShape::Square || cell.width() < 3 { return canvas.fill_rect(cell); }
+
+        let r = cell.width() as f64 / 2.0;
+        let spans: Vec<Rect> = (0..cell.height()).map(|row| {
+            let dy = row as f64 + 0.5 - r;
+            let half = (r * r - dy * dy).max(0.0).sqrt();
+            let x = cell.x() + (r - half).round() as i32;
+            Rect::new(x, cell.y() + row as i32, cmp::max(1, (2.0 * half).round() as u32), 1)
+        }).collect();
+        canvas.fill_rects(&spans)
+    }
+}
+/*
+ * FRAME STATS
+ * Durations of recently rendered frames, drawn as scrolling bar graph with --show-stats.
+ */
+pub struct FrameStats {
+    times: VecDeque<Duration>,
+    last: Instant,
+}
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl FrameStats {
     pub fn new() -> Self {
-        let instset: Vec<(&'static str, Box<FnMut(ChunkedOp, &mut State)>)> = vec![
-            /*
-             * 00E0 - CLS
-             * Clear the display.  
-             */
-            ("00E0", Box::new(|_, state|  state.display.cls())),
-            /*
-            * 00EE - RET
-            * Return from a subroutine.
-            * The interpreter sets the program counter to the address at the top of the stack, then subtracts 1 from the stack pointer.
-            */
-            ("00EE", Box::new(|_, state| {
-                state.reg.PC = state.stack[state.reg.SP as usize];
-                state.reg.SP -= 1;
-            })),
-            /*
-             * 1nnn - JP addr
-             * Jump to location nnn.
-             */
-            ("1nnn", Box::new(|(_, a, b, c), state| {
-                let addr = ((a << 8) + (b << 4) + c) as u16;
-                state.reg.PC = addr;
-            })),
-            /*
-            * 2nnn - CALL addr
-            * Call subroutine at nnn.
-            * The interpreter increments the stack pointer, then puts the current PC on the top of the stack. The PC is then set to nnn.
-            */
-            ("2nnn", Box::new(|(_, a, b, c), state| {
-                state.reg.SP += 1;
-                state.stack[state.reg.SP as usize] = state.reg.PC;
-                let addr = ((a << 8) + (b << 4) + c) as u16;
-                state.reg.PC = addr;
-            })),
-            /*
-             * 3xkk - SE Vx, byte
-             * Skip next instruction if Vx = kk.
-             * The interpreter compares register Vx to kk, and if they are equal, increments the program counter by 2.
-            */
-            ("3xkk", Box::new(|(_, x, k1, k2), state| {
-                let kk = ((k1 << 4) + k2) as u8;
-                if state.reg.V[x] == kk {
-                    state.reg.PC += 2;
-                }
-            })),
-            /*
-             * 4xkk - SNE Vx, byte
-             * Skip next instruction if Vx != kk.
-             * The interpreter compares register Vx to kk, and if they are not equal, increments the program counter by 2.
-             */
-            ("4xkk", Box::new(|(_, x, k1, k2), state| {
-                let kk = ((k1 << 4) + k2) as u8;
-                if state.reg.V[x] != kk {
-                    state.reg.PC += 2;
-                }
-            })),
-            /*
-             * 5xy0 - SE Vx, Vy
-             * Skip next instruction if Vx = Vy.
-             * The interpreter compares register Vx to register Vy, and if they are equal, increments the program counter by 2.
-             */
-            ("5xy0", Box::new(|(_, x, y, _), state| {
-                if state.reg.V[x] == state.reg.V[y] {
-                    state.reg.PC += 2;
-                }
-            })),
-            /*
-             * 6xkk - LD Vx, byte
-             * Set Vx = kk.
-             * The interpreter puts the value kk into register Vx.
-             */
-            ("6xkk", Box::new(|(_, x, k1, k2), state| {
-                let kk = ((k1 << 4) + k2) as u8;
-                state.reg.V[x] = kk;
-            })),
-            /*
-             * 7xkk - ADD Vx, byte
-             * Set Vx = Vx + kk.
-             * Adds the value kk to the value of register Vx, then stores the result in Vx. 
-             */
-            ("7xkk", Box::new(|(_, x, k1, k2), state| {
-                let kk = ((k1 << 4) + k2) as u8;
-                state.reg.V[x] = (Wrapping(state.reg.V[x]) + Wrapping(kk)).0;
-            })),
-            /*
-             * 8xy0 - LD Vx, Vy
-             * Set Vx = Vy.
-             * Stores the value of register Vy in register Vx.
-             */
-            ("8xy0", Box::new(|(_, x, y, _), state| {
-                state.reg.V[x] = state.reg.V[y];
+        FrameStats { times: VecDeque::with_capacity(STATS_FRAMES), last: Instant::now() }
+    }
+
+    /*
+     * Called once per presented frame.
+     */
+    pub fn record(&mut self) {
+        if self.times.len() == STATS_FRAMES { self.times.pop_front(); }
+        self.times.push_back(self.last.elapsed());
+        self.last = Instant::now();
+    }
+
+    /*
+     * Draws graph in bottom left corner, one 2px bar per frame, 1px per millisecond. Frames slower than 60Hz
+     * are red, dashed line marks 60Hz.
+     */
+    pub fn draw(&self, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, fg: Color, bg: Color) -> Result<(), String> {
+        const MAX_MS: u32 = 50;
+        let (_, height) = canvas.output_size()?;
+        let top = height as i32 - MAX_MS as i32;
+        canvas.set_draw_color(bg);
+        canvas.fill_rect(Rect::new(0, top, 2 * STATS_FRAMES as u32, MAX_MS))?;
+        for (i, time) in self.times.iter().enumerate() {
+            let ms = (time.as_millis() as u32).clamp(1, MAX_MS);
+            canvas.set_draw_color(if ms > 17 { Color::RGB(255, 0, 0) } else { fg });
+            canvas.fill_rect(Rect::new(2 * i as i32, height as i32 - ms as i32, 2, ms))?;
+        }
+        canvas.set_draw_color(fg);
+        for x in (0..2 * STATS_FRAMES as i32).step_by(4) {
+            canvas.draw_point((x, height as i32 - 17))?;
+        }
+        let total = self.times.iter().sum::<Duration>().as_secs_f64();
+        if total > 0.0 {
+            let fps = format!("{:.0} FPS", self.times.len() as f64 / total);
+            font::draw_label(canvas, &fps, 0, top - 14, 2, fg, bg)?;
+        }
+        Ok(())
+    }
+}
+/*
+ * OPCODE HISTOGRAM
+ * Instructions executed during the last second per opcode group, drawn as bar chart with --opcode-histogram.
+ */
+pub struct OpcodeHistogram {
+    // Group counters at last update
+    last: [u64; 16],
+    per_second: [u64; 16],
+    updated: Instant,
+}
+impl OpcodeHistogram {
+    pub fn new(state: &State) -> Self {
+        OpcodeHistogram { last: state.op_groups, per_second: [0; 16], updated: Instant::now() }
+    }
+
+    /*
+     * Called once per presented frame, takes new counts once a second passed.
+     */
+    pub fn update(&mut self, state: &State) {
+        let elapsed = self.updated.elapsed();
+        if elapsed < Duration::from_secs(1) { return; }
+        for group in 0..16 {
+            let delta = state.op_groups[group] - self.last[group];
+            self.per_second[group] = (delta as f64 / elapsed.as_secs_f64()) as u64;
+        }
+        self.last = state.op_groups;
+        self.updated = Instant::now();
+    }
+
+    /*
+     * Draws chart in top right corner, one bar per group labelled with its hex digit, the busiest group fills it.
+     */
+    pub fn draw(&self, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, fg: Color, bg: Color) -> Result<(), String> {
+        const BAR: u32 = 8;
+        const HEIGHT: u32 = 48;
+        let (width, _) = canvas.output_size()?;
+        let left = width as i32 - 16 * BAR as i32 - 2;
+        let label = font::GLYPH_HEIGHT as i32 + 2;
+        canvas.set_draw_color(bg);
+        canvas.fill_rect(Rect::new(left - 2, 0, 16 * BAR + 4, HEIGHT + 3 * label as u32 + 2))?;
+        let total: u64 = self.per_second.iter().sum();
+        font::draw_text(canvas, &format!("{} IPS", total), left, 2, 1, fg)?;
+        let busiest = cmp::max(*self.per_second.iter().max().unwrap_or(&0), 1);
+        let bottom = label + 1 + HEIGHT as i32;
+        canvas.set_draw_color(fg);
+        for (group, count) in self.per_second.iter().enumerate() {
+            let bar = (count * HEIGHT as u64 / busiest) as u32;
+            let x = left + (group as u32 * BAR) as i32;
+            if bar > 0 { canvas.fill_rect(Rect::new(x, bottom - bar as i32, BAR - 2, bar))?; }
+            font::draw_text(canvas, &format!("{:X}", group), x + 1, bottom + 2, 1, fg)?;
+        }
+        Ok(())
+    }
+}
+/*
+ * FRAME DUMP
+ * Rendered frames written as frame_000001.png, frame_000002.png... into the --frame-dump directory, one pixel per
+ * display cell. Numbers have no gaps, also when unchanged frames are skipped with --frame-dump-changed.
+ */
+pub struct FrameDump {
+    dir: path::PathBuf,
+    changed_only: bool,
+    frames: u64,
+    bytes: u64,
+}
+impl FrameDump {
+    pub fn new(dir: &str, changed_only: bool, display: &Display) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir, e))?;
+        // Frames are stored uncompressed, so their size is known up front
+        let frame = display.width * display.height * 4 + display.height + 70;
+        eprintln!("Warning: --frame-dump writes {} KB per frame, up to {} MB per minute at 60 frames/s", frame / 1024,
+            frame * 60 * 60 / (1024 * 1024));
+        Ok(FrameDump { dir: path::PathBuf::from(dir), changed_only: changed_only, frames: 0, bytes: 0 })
+    }
+
+    /*
+     * Called once per rendered frame, changed telling whether display was drawn to since previous one.
+     */
+    pub fn record(&mut self, display: &Display, changed: bool, fg: Color, bg: Color) -> Result<(), String> {
+        if self.changed_only && !changed { return Ok(()); }
+        self.frames += 1;
+        let file = self.dir.join(format!("frame_{:06}.png", self.frames));
+        let image = png::encode(display.width as u32, display.height as u32, &display.rgba_buffer(1, fg, bg));
+        fs::write(&file, &image).map_err(|e| format!("Unable to write {}: {}", file.display(), e))?;
+        let before = self.bytes >> 30;
+        self.bytes += image.len() as u64;
+        if self.bytes >> 30 > before {
+            eprintln!("Warning: --frame-dump has written {} GB in {} frames", self.bytes >> 30, self.frames);
+        }
+        Ok(())
+    }
+}
+/*
+ * NATIVE SCREEN
+ * Streaming texture of one texel per display pixel for --gpu-scale, stretched onto the window by the renderer
+ * with nearest neighbour filtering. Only uploaded when the image changed.
+ */
+pub struct NativeScreen<'a> {
+    texture: sdl2::render::Texture<'a>,
+    image: Vec<u8>,
+}
+impl<'a> NativeScreen<'a> {
+    pub fn new(texture_creator: &'a sdl2::render::TextureCreator<sdl2::video::WindowContext>,
+               display: &Display) -> Result<NativeScreen<'a>, String> {
+        sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", "nearest");
+        let texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGBA32, display.width as u32, display.height as u32)
+            .map_err(|e| e.to_string())?;
+        Ok(NativeScreen {texture: texture, image: Vec::new()})
+    }
+
+    fn draw(&mut self, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, display: &Display, app: &App) -> Result<(), String> {
+        let image = display.rgba_buffer(1, app.fg(), app.bg());
+        if image != self.image {
+            self.texture.update(None, &image, display.width * 4).map_err(|e| e.to_string())?;
+            self.image = image;
+        }
+        let cell = app.transform.cell;
+        let (width, height) = (display.width as u32 * cell, display.height as u32 * cell);
+        canvas.copy(&self.texture, None, Rect::new(app.transform.x, app.transform.y, width, height))
+    }
+}
+
+/*
+ * Draws display into app.transform, square pixels through a texture, other shapes cell by cell. With a native
+ * screen square pixels are scaled by the GPU instead of uploading a texture of the window size every frame.
+ */
+fn draw_display(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+                texture_creator: &sdl2::render::TextureCreator<sdl2::video::WindowContext>,
+                native: Option<&mut NativeScreen>, display: &Display, app: &App) -> Result<(), String> {
+    if let (Some(native), PixelShape::Square) = (native, app.shape) {
+        native.draw(canvas, display, app)?;
+    } else if app.shape == PixelShape::Square {
+        let cell = app.transform.cell;
+        let (width, height) = (display.width as u32 * cell, display.height as u32 * cell);
+        let mut texture = texture_creator.create_texture_static(PixelFormatEnum::RGBA32, width, height)
+            .map_err(|e| e.to_string())?;
+        let image = display.rgba_buffer(cell as usize, app.fg(), app.bg());
+        texture.update(None, &image, width as usize * 4).map_err(|e| e.to_string())?;
+        canvas.copy(&texture, None, Rect::new(app.transform.x, app.transform.y, width, height))?;
+    } else {
+        let cell = app.transform.cell;
+        canvas.set_draw_color(app.bg());
+        canvas.fill_rect(Rect::new(app.transform.x, app.transform.y, display.width as u32 * cell, display.height as u32 * cell))?;
+        for i in 0..display.height {
+            for j in 0..display.width {
+                if display.lit(i, j) {
+                    canvas.set_draw_color(shade(app.fg(), app.bg(), display.intensity(i, j)));
+                    app.shape.fill(canvas, app.transform.rect(i, j))?;
+                }
+            }
+        }
+    }
+    if display.show_wrap { draw_wrap(canvas, display, app)?; }
+    Ok(())
+}
+
+/*
+ * Outlines display edge for --show-wrap. Every lit pixel which got there by wrapping is outlined, and ghosted
+ * at its unwrapped position in the area past the edge.
+ */
+fn draw_wrap(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, display: &Display, app: &App) -> Result<(), String> {
+    let cell = app.transform.cell;
+    canvas.set_draw_color(shade(app.fg(), app.bg(), 0x60));
+    canvas.draw_rect(Rect::new(app.transform.x - 1, app.transform.y - 1,
+        display.width as u32 * cell + 2, display.height as u32 * cell + 2))?;
+    for i in 0..display.height {
+        for j in 0..display.width {
+            if let Some((row, col)) = display.wrapped_from(i, j) {
+                canvas.fill_rect(app.transform.rect(row, col))?;
+                canvas.draw_rect(app.transform.rect(i, j))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Registers, next instruction, timers, held keys and memory at I in top right corner, drawn while debug overlay key is held.
+ * Paused, return addresses of the call stack follow, innermost call first.
+ */
+fn draw_debug_overlay(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, state: &State, paused: bool, lenient: bool,
+                      sticky: bool, fg: Color, bg: Color) -> Result<(), String> {
+    let reg = &state.reg;
+    let next = state.peek_decoded(lenient).unwrap_or_else(|| String::from("?"));
+    let mut lines = vec![
+        format!("PC {:03X} I {:03X} SP {}", reg.PC, reg.I, reg.SP),
+        format!("{} {}", state.peek_opcode().map_or(String::from("????"), |op| format!("{:04X}", op)), next),
+        format!("DT {:02X} ST {:02X}", reg.DT, reg.ST),
+        format!("SEED {}", state.seed),
+    ];
+    for (i, values) in reg.V.chunks(4).enumerate() {
+        let regs: Vec<String> = values.iter().enumerate().map(|(j, v)| format!("V{:X} {:02X}", 4 * i + j, v)).collect();
+        lines.push(regs.join(" "));
+    }
+    let keys: Vec<String> = (0..16).filter(|k| state.is_key_down(*k)).map(|k| format!("{:X}", k)).collect();
+    // Keys latched by --sticky-keys
+    let label = if sticky { "STUCK" } else { "KEYS" };
+    lines.push(format!("{} {}", label, if keys.is_empty() { String::from("-") } else { keys.join(" ") }));
+    let bytes: Vec<String> = (0..8).filter_map(|i| state.mem.get(reg.I as usize + i)).map(|b| format!("{:02X}", b)).collect();
+    lines.push(format!("[I] {}", bytes.join(" ")));
+    if paused {
+        let depth = cmp::min(reg.SP as usize, state.stack.len());
+        lines.push(format!("STACK {}/{}", depth, state.stack_depth));
+        for (level, addr) in state.stack[..depth].iter().enumerate().rev() {
+            lines.push(format!("{:2} {:03X}", level, addr));
+        }
+    }
+
+    let (width, _) = canvas.output_size()?;
+    let line_height = (font::GLYPH_HEIGHT + 2) as i32 * 2;
+    let left = width as i32 - lines.iter().map(|l| font::text_size(l, 2).0 as i32 + 2).max().unwrap_or(0);
+    for (i, line) in lines.iter().enumerate() {
+        font::draw_label(canvas, line, left, i as i32 * line_height, 2, fg, bg)?;
+    }
+    draw_memory_map(canvas, state)
+}
+
+/*
+ * Strip of MEMORY_MAP_CELLS cells in the bottom right corner, each one covering an equal slice of memory.
+ * Colored by what the slice holds, first match wins: PC white, I yellow, written by the ROM red, font blue,
+ * loaded from the ROM green, untouched gray.
+ */
+fn draw_memory_map(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, state: &State) -> Result<(), String> {
+    let size = state.mem.len();
+    let per_cell = cmp::max(size / MEMORY_MAP_CELLS, 1);
+    let rows = size.div_ceil(per_cell * MEMORY_MAP_COLUMNS);
+    let (width, height) = canvas.output_size()?;
+    let left = width as i32 - (MEMORY_MAP_COLUMNS as u32 * MEMORY_MAP_CELL) as i32;
+    let top = height as i32 - (rows as u32 * MEMORY_MAP_CELL) as i32;
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.fill_rect(Rect::new(left - 1, top - 1, MEMORY_MAP_COLUMNS as u32 * MEMORY_MAP_CELL + 1, rows as u32 * MEMORY_MAP_CELL + 1))?;
+
+    let (pc, i) = (state.reg.PC as usize, state.reg.I as usize);
+    for (cell, start) in (0..size).step_by(per_cell).enumerate() {
+        let slice = start..cmp::min(start + per_cell, size);
+        let color = if slice.contains(&pc) {
+            Color::RGB(255, 255, 255)
+        } else if slice.contains(&i) {
+            Color::RGB(255, 220, 0)
+        } else if state.written[slice.clone()].contains(&true) {
+            Color::RGB(220, 40, 40)
+        } else if start < FONT.len() {
+            Color::RGB(60, 110, 255)
+        } else if state.initialized[slice].contains(&true) {
+            Color::RGB(40, 180, 70)
+        } else {
+            Color::RGB(50, 50, 50)
+        };
+        let (row, col) = (cell / MEMORY_MAP_COLUMNS, cell % MEMORY_MAP_COLUMNS);
+        canvas.set_draw_color(color);
+        canvas.fill_rect(Rect::new(left + (col as u32 * MEMORY_MAP_CELL) as i32, top + (row as u32 * MEMORY_MAP_CELL) as i32,
+                                   MEMORY_MAP_CELL - 1, MEMORY_MAP_CELL - 1))?;
+    }
+    Ok(())
+}
+/*
+ * APP
+ * Frontend settings which can be changed while running.
+ */
+pub struct App {
+    pub scale: u32,
+    pub theme: usize,
+    pub transform: Transform,
+    pub shape: PixelShape,
+    // Index into quirks::PROFILES, None for default quirks
+    pub profile: Option<usize>,
+    // Message shown on screen for a moment
+    pub toast: Option<(String, Instant)>,
+    // Swaps theme colors
+    pub inverted: bool,
+    // --bg-color in place of theme background, --border-color around display
+    pub background: Option<Color>,
+    pub border: Option<Color>,
+}
+impl App {
+    pub fn theme(&self) -> &Theme {
+        &THEMES[self.theme]
+    }
+
+    /*
+     * Colors of lit and unlit cells, theme colors swapped when inverted.
+     */
+    pub fn fg(&self) -> Color {
+        if self.inverted { self.unlit() } else { self.theme().fg }
+    }
+
+    pub fn bg(&self) -> Color {
+        if self.inverted { self.theme().fg } else { self.unlit() }
+    }
+
+    fn unlit(&self) -> Color {
+        self.background.unwrap_or(self.theme().bg)
+    }
+
+    /*
+     * Color of the window outside display, background unless set apart.
+     */
+    pub fn border(&self) -> Color {
+        self.border.unwrap_or_else(|| self.bg())
+    }
+
+    pub fn next_theme(&mut self) {
+        self.theme = (self.theme + 1) % THEMES.len();
+    }
+
+    pub fn notify(&mut self, text: String) {
+        self.toast = Some((text, Instant::now()));
+    }
+
+    /*
+     * Switches to next built-in profile, returning its quirks.
+     */
+    pub fn next_profile(&mut self) -> Quirks {
+        let next = self.profile.map_or(0, |i| (i + 1) % quirks::PROFILES.len());
+        let (name, quirks) = quirks::PROFILES[next];
+        self.profile = Some(next);
+        self.notify(format!("Profile: {}", name));
+        quirks
+    }
+
+    /*
+     * Sets window scale. Returns true if it changed, in which case window has to be resized.
+     */
+    pub fn set_scale(&mut self, scale: u32) -> bool {
+        let scale = scale.clamp(1, MAX_SCALE);
+        let changed = scale != self.scale;
+        self.scale = scale;
+        changed
+    }
+}
+/*
+ * DIAGNOSTICS
+ * Optional logs and checks for debugging ROMs, all off by default.
+ */
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    // Print every Dxyn with coordinates, height, wrapping and collision
+    pub log_draws: bool,
+    // Print when PC enters memory written during this run
+    pub detect_smc: bool,
+    // Print when Fx65 or Dxyn read memory which was never loaded or written
+    pub detect_uninit: bool,
+    // Panic when Ex9E/ExA1 key register is above 0xF, instead of using its low nibble
+    pub strict_keys: bool,
+    // Panic when an instruction is fetched from an odd address
+    pub strict_alignment: bool,
+    // Print the first write to every address of the loaded ROM
+    pub warn_code_overwrite: bool,
+    // Keep DT and ST unchanged while CPU runs, so delay loops never finish
+    pub freeze_timers: bool,
+    // Write or compare a line for every instruction
+    pub tracer: Option<Tracer>,
+    // Pause the debugger after a Dxyn which set VF
+    pub break_on_collision: bool,
+    // Address of such Dxyn, until the debugger picks it up
+    collision_at: Option<u16>,
+    // Whether previous fetch came from written memory, so entering region is reported once
+    in_smc: bool,
+}
+/*
+ * STATE
+ * Contains all vartiables needed for executions(regs, memory, dispaly, etc.)
+ */
+pub struct State {
+    // Use read_mem/write_mem from instructions
+    pub mem: Mem,
+    // Addresses written by instructions since reset
+    pub written: Vec<bool>,
+    // Addresses holding font, ROM or anything written since reset
+    pub initialized: Vec<bool>,
+    // Size of ROM loaded at ENTRY_POINT by reset
+    pub rom_len: usize,
+    pub stack: Stack,
+    // Maximum number of nested calls
+    pub stack_depth: usize,
+    pub reg: Reg,
+    pub display: Display,
+    // Writes to 0xF00-0xFFF show up on display, like on COSMAC VIP
+    pub vip_display: bool,
+    // ST was nonzero at last audio_events
+    beeping: bool,
+    // DT decrements since last audio_events
+    dt_ticks: usize,
+    // Use set_key/is_key_down
+    key: Keyboard,
+    pub quirks: Quirks,
+    // Seeded, so replays are deterministic
+    pub rng: StdRng,
+    // Seed rng was last started from
+    pub seed: u64,
+
+    // Index of register to put key idx into
+    pub awaiting_input: u8,
+    // Key pressed while Fx0A waits for its release, 0xFF if none
+    pub held_key: u8,
+    // Keys already down when Fx0A began, they don't complete it until released and pressed again
+    pub stale_keys: Keyboard,
+    // Dxyn executed during current frame
+    pub frame_draws: u32,
+    // Total executed instructions
+    pub instructions: u64,
+    // Executed instructions by highest nibble of opcode
+    pub op_groups: [u64; 16],
+    // Total Dxyn executed and how many of them set VF
+    pub draws: u64,
+    pub collisions: u64,
+    pub diag: Diagnostics,
+}
+impl State {
+    /*
+     * Checked memory accessors, all memory traffic of instructions goes through them.
+     */
+    pub fn read_mem(&self, addr: usize) -> u8 {
+        if addr >= self.mem.len() { panic!("Memory read out of bounds: {:#X} at {:#05X}", addr, self.reg.PC.wrapping_sub(2)); }
+        self.mem[addr]
+    }
+
+    pub fn write_mem(&mut self, addr: usize, val: u8) {
+        let addr = self.data_addr(addr);
+        if addr >= self.mem.len() { panic!("Memory write out of bounds: {:#X} at {:#05X}", addr, self.reg.PC.wrapping_sub(2)); }
+        let rom = ENTRY_POINT as usize..ENTRY_POINT as usize + self.rom_len;
+        if self.diag.warn_code_overwrite && rom.contains(&addr) && !self.written[addr] {
+            println!("{:#05X} overwrites ROM at {:#05X}", self.reg.PC.wrapping_sub(2), addr);
+        }
+        self.mem[addr] = val;
+        if self.vip_display && (VIP_DISPLAY_START..VIP_DISPLAY_END).contains(&addr) {
+            self.display.set_packed_byte(addr - VIP_DISPLAY_START, val);
+        }
+        self.written[addr] = true;
+        self.initialized[addr] = true;
+    }
+
+    /*
+     * Address of data access relative to I, wrapped to memory size with wrap_i_addressing quirk.
+     */
+    fn data_addr(&self, addr: usize) -> usize {
+        if self.quirks.wrap_i_addressing { addr % self.mem.len() } else { addr }
+    }
+
+    /*
+     * read_mem for data reads of Fx65 and Dxyn. With --detect-uninit reports first read of every address which
+     * wasn't loaded or written.
+     */
+    pub fn read_data(&mut self, addr: usize) -> u8 {
+        let addr = self.data_addr(addr);
+        let val = self.read_mem(addr);
+        if self.diag.detect_uninit && !self.initialized[addr] {
+            println!("{:#05X} reads uninitialized memory at {:#05X}", self.reg.PC.wrapping_sub(2), addr);
+            self.initialized[addr] = true;
+        }
+        val
+    }
+
+    /*
+     * Audio events since last call, polled by frontends once per frame. Beep is only reported on change.
+     */
+    pub fn audio_events(&mut self) -> Vec<AudioEvent> {
+        let mut events = vec![AudioEvent::Tick; self.dt_ticks];
+        self.dt_ticks = 0;
+        if self.beeping != (self.reg.ST > 0) {
+            self.beeping = !self.beeping;
+            events.push(AudioEvent::Beep { on: self.beeping });
+        }
+        events
+    }
+
+    /*
+     * Opcode at PC, fetched without advancing PC or executing it. None if PC is on the last byte of memory or past it.
+     */
+    pub fn peek_opcode(&self) -> Option<u16> {
+        let pc = self.reg.PC as usize;
+        Some(((*self.mem.get(pc)? as u16) << 8) | *self.mem.get(pc + 1)? as u16)
+    }
+
+    /*
+     * Mnemonic of opcode at PC, None if it can't be decoded.
+     */
+    pub fn peek_decoded(&self, lenient: bool) -> Option<String> {
+        let op = self.peek_opcode()?;
+        decode(nibbles(op), lenient).map(|_| disasm::mnemonic(op, lenient))
+    }
+
+    /*
+     * Lowest and highest address written since reset, None if nothing was.
+     */
+    pub fn written_range(&self) -> Option<(usize, usize)> {
+        let lowest = self.written.iter().position(|w| *w)?;
+        let highest = self.written.iter().rposition(|w| *w)?;
+        Some((lowest, highest))
+    }
+
+    /*
+     * Skips next instruction, XO-CHIP F000 NNNN being two words long. That's the case for every ROM, a CHIP-8 one
+     * with a F000 data word after a skip steps over the word following it too. PC wraps at 16 bits.
+     */
+    pub fn skip(&mut self) {
+        let pc = self.reg.PC as usize;
+        let long = self.mem.get(pc) == Some(&0xF0) && self.mem.get(pc + 1) == Some(&0x00);
+        self.reg.PC = self.reg.PC.wrapping_add(if long { 4 } else { 2 });
+    }
+
+    /*
+     * Keypad access for input sources(keyboard, scripts, embedders). Index is keypad value 0-F.
+     */
+    pub fn set_key(&mut self, index: usize, pressed: bool) -> Result<(), String> {
+        if index >= KEYBOARD_SIZE { return Err(format!("Invalid keypad index {}", index)); }
+        self.key[index] = pressed;
+        Ok(())
+    }
+
+    /*
+     * Key events for code driving the VM between steps. Unlike set_key they complete a pending Fx0A right away:
+     * press stores the key, or with fx0a_on_release quirk remembers it so its release stores it. Key that is
+     * pressed and released between two steps still completes Fx0A.
+     */
+    pub fn press(&mut self, index: usize) -> Result<(), String> {
+        self.set_key(index, true)?;
+        resolve_key_wait(self, index as u8);
+        Ok(())
+    }
+
+    pub fn release(&mut self, index: usize) -> Result<(), String> {
+        self.set_key(index, false)?;
+        resolve_key_wait(self, 0xFF);
+        Ok(())
+    }
+
+    /*
+     * Restarts Cxkk random numbers from seed, same sequence as a run started with --seed.
+     */
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.seed = seed;
+    }
+
+    pub fn is_key_down(&self, index: usize) -> bool {
+        self.key.get(index).cloned().unwrap_or(false)
+    }
+
+    pub fn keypad(&self) -> Keyboard {
+        self.key
+    }
+
+    /*
+     * Keypad key named by Vx. Only low nibble is used, unless --strict-keys requires Vx to be 0-F.
+     */
+    pub fn keypad_index(&self, x: usize) -> usize {
+        let value = self.reg.V[x];
+        if self.diag.strict_keys && value > 0xF {
+            panic!("{:#05X}: V{:X}={:#04X} is not a keypad key", self.reg.PC - 2, x, value);
+        }
+        (value & 0xF) as usize
+    }
+
+    /*
+     * Lowest pressed key for awaiting input functionality, 0xFF if none.
+     */
+    pub fn pressed_key(&self) -> u8 {
+        self.key.iter().position(|down| *down).map_or(0xFF, |i| i as u8)
+    }
+
+    /*
+     * Puts machine into power-on state with font and ROM loaded.
+     */
+    pub fn reset(&mut self, rom: &[u8]) {
+        // Size set by --ram-size stays
+        let size = self.mem.len();
+        self.mem = vec![0u8; size];
+        self.mem[..FONT.len()].copy_from_slice(&FONT);
+        self.mem[ENTRY_POINT as usize..ENTRY_POINT as usize + rom.len()].copy_from_slice(rom);
+        self.rom_len = rom.len();
+        self.written = vec![false; size];
+        self.initialized = vec![false; size];
+        for addr in (0..FONT.len()).chain(ENTRY_POINT as usize..ENTRY_POINT as usize + rom.len()) {
+            self.initialized[addr] = true;
+        }
+
+        self.stack.clear();
+        self.reg = Reg::new();
+        self.reg.PC = ENTRY_POINT;
+        self.display.cls();
+        self.key = [false; KEYBOARD_SIZE];
+        self.awaiting_input = 0xFF;
+        self.held_key = 0xFF;
+        self.stale_keys = [false; KEYBOARD_SIZE];
+    }
+
+    /*
+     * Registers, stack and FNV-1a hashes of memory and display, for external tools.
+     */
+    pub fn to_json(&self) -> serde_json::Value {
+        let pixels: Vec<u8> = self.display.buffer.iter().flatten()
+            .map(|p| if self.display.grayscale { *p } else { (*p != 0) as u8 }).collect();
+        json!({
+            "pc": self.reg.PC,
+            "i": self.reg.I,
+            "sp": self.reg.SP,
+            "dt": self.reg.DT,
+            "st": self.reg.ST,
+            "v": self.reg.V,
+            "stack": self.stack,
+            "memory_hash": format!("{:016x}", quirks::fnv1a(&self.mem)),
+            "display_hash": format!("{:016x}", quirks::fnv1a(&pixels)),
+            "display": self.display.packed_bits().iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        })
+    }
+
+    /*
+     * Memory, stack, registers and display for --save-state-b64.
+     */
+    pub fn snapshot(&self) -> savestate::Snapshot {
+        savestate::Snapshot {pc: self.reg.PC, i: self.reg.I, sp: self.reg.SP, dt: self.reg.DT, st: self.reg.ST, v: self.reg.V,
+            awaiting_input: self.awaiting_input, stack: self.stack.clone(), width: self.display.width as u8,
+            height: self.display.height as u8, display: self.display.packed_bits(), mem: self.mem.clone()}
+    }
+
+    /*
+     * Replaces machine state with a snapshot taken with the same memory size and stack depth. Restored memory
+     * counts as loaded, not as written by the ROM.
+     */
+    pub fn restore(&mut self, snapshot: &savestate::Snapshot) -> Result<(), String> {
+        if snapshot.mem.len() != self.mem.len() {
+            return Err(format!("Save state has {} bytes of memory, --ram-size is {}", snapshot.mem.len(), self.mem.len()));
+        }
+        if snapshot.stack.len() > self.stack_depth || snapshot.sp as usize > self.stack_depth {
+            return Err(format!("Save state has {} return addresses, --stack-depth is {}", snapshot.stack.len(), self.stack_depth));
+        }
+        if (snapshot.width as usize, snapshot.height as usize) != (self.display.width, self.display.height) {
+            return Err(format!("Save state display is {}x{}", snapshot.width, snapshot.height));
+        }
+        if snapshot.pc as usize + 1 >= self.mem.len() {
+            return Err(format!("Save state PC {:#05X} is past end of memory", snapshot.pc));
+        }
+        if snapshot.sp as usize != snapshot.stack.len() {
+            return Err(format!("Save state stack pointer is {} with {} return addresses", snapshot.sp, snapshot.stack.len()));
+        }
+        if snapshot.awaiting_input != 0xFF && snapshot.awaiting_input > 0xF {
+            return Err(format!("Save state waits for key into invalid register {:X}", snapshot.awaiting_input));
+        }
+        self.mem = snapshot.mem.clone();
+        self.written = vec![false; self.mem.len()];
+        self.initialized = vec![true; self.mem.len()];
+        self.stack = snapshot.stack.clone();
+        self.reg = Reg {V: snapshot.v, I: snapshot.i, DT: snapshot.dt, ST: snapshot.st, PC: snapshot.pc, SP: snapshot.sp};
+        self.awaiting_input = snapshot.awaiting_input;
+        self.held_key = 0xFF;
+        self.stale_keys = self.key;
+        self.display.cls();
+        for (index, byte) in snapshot.display.iter().enumerate() {
+            self.display.set_packed_byte(index, *byte);
+        }
+        Ok(())
+    }
+
+    /*
+     * Writes raw RAM to path, and registers to a text sidecar next to it(path + ".regs").
+     */
+    pub fn dump_memory_to(&self, path: &path::Path) -> io::Result<()> {
+        fs::write(path, &self.mem[..])?;
+
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".regs");
+        let mut file = fs::File::create(sidecar)?;
+        writeln!(file, "PC={:#05X}", self.reg.PC)?;
+        writeln!(file, "I={:#05X}", self.reg.I)?;
+        writeln!(file, "SP={}", self.reg.SP)?;
+        writeln!(file, "DT={}", self.reg.DT)?;
+        writeln!(file, "ST={}", self.reg.ST)?;
+        for (i, v) in self.reg.V.iter().enumerate() {
+            writeln!(file, "V{:X}={:#04X}", i, v)?;
+        }
+        let stack: Vec<String> = self.stack.iter().map(|addr| format!("{:#05X}", addr)).collect();
+        writeln!(file, "stack={}", stack.join(" "))?;
+        Ok(())
+    }
+}
+/*
+ * INSTRUCTIONS
+ */
+pub struct Inst {
+    instructions: HashMap<&'static str, Box<FnMut(ChunkedOp, &mut State)>>,
+    // Decode 5xyN and 9xyN with nonzero N as 5xy0 and 9xy0 instead of panicking
+    pub lenient: bool,
+}
+impl Inst{
+    pub fn new() -> Self {
+        let instset: Vec<(&'static str, Box<FnMut(ChunkedOp, &mut State)>)> = vec![
+            /*
+             * 00E0 - CLS
+             * Clear the display.  
+             */
+            ("00E0", Box::new(|_, state|  state.display.cls())),
+            /*
+            * 00EE - RET
+            * Return from a subroutine.
+            * The interpreter sets the program counter to the address at the top of the stack, then subtracts 1 from the stack pointer.
+            */
+            ("00EE", Box::new(|_, state| {
+                let at = state.reg.PC - 2;
+                state.reg.PC = state.stack.pop()
+                    .unwrap_or_else(|| panic!("Stack underflow: RET with empty stack at {:#05X}", at));
+                state.reg.SP = state.stack.len() as u8;
+            })),
+            /*
+             * 1nnn - JP addr
+             * Jump to location nnn.
+             */
+            ("1nnn", Box::new(|(_, a, b, c), state| {
+                let addr = ((a << 8) + (b << 4) + c) as u16;
+                state.reg.PC = addr;
+            })),
+            /*
+            * 2nnn - CALL addr
+            * Call subroutine at nnn.
+            * The interpreter increments the stack pointer, then puts the current PC on the top of the stack. The PC is then set to nnn.
+            */
+            ("2nnn", Box::new(|(_, a, b, c), state| {
+                if state.stack.len() >= state.stack_depth {
+                    panic!("Stack overflow: more than {} nested calls at {:#05X}", state.stack_depth, state.reg.PC - 2);
+                }
+                state.stack.push(state.reg.PC);
+                state.reg.SP = state.stack.len() as u8;
+                let addr = ((a << 8) + (b << 4) + c) as u16;
+                state.reg.PC = addr;
+            })),
+            /*
+             * 3xkk - SE Vx, byte
+             * Skip next instruction if Vx = kk.
+             * The interpreter compares register Vx to kk, and if they are equal, increments the program counter by 2.
+            */
+            ("3xkk", Box::new(|(_, x, k1, k2), state| {
+                let kk = ((k1 << 4) + k2) as u8;
+                if state.reg.V[x] == kk {
+                    state.skip();
+                }
+            })),
+            /*
+             * 4xkk - SNE Vx, byte
+             * Skip next instruction if Vx != kk.
+             * The interpreter compares register Vx to kk, and if they are not equal, increments the program counter by 2.
+             */
+            ("4xkk", Box::new(|(_, x, k1, k2), state| {
+                let kk = ((k1 << 4) + k2) as u8;
+                if state.reg.V[x] != kk {
+                    state.skip();
+                }
+            })),
+            /*
+             * 5xy0 - SE Vx, Vy
+             * Skip next instruction if Vx = Vy.
+             * The interpreter compares register Vx to register Vy, and if they are equal, increments the program counter by 2.
+             */
+            ("5xy0", Box::new(|(_, x, y, _), state| {
+                if state.reg.V[x] == state.reg.V[y] {
+                    state.skip();
+                }
+            })),
+            /*
+             * 6xkk - LD Vx, byte
+             * Set Vx = kk.
+             * The interpreter puts the value kk into register Vx.
+             */
+            ("6xkk", Box::new(|(_, x, k1, k2), state| {
+                let kk = ((k1 << 4) + k2) as u8;
+                state.reg.V[x] = kk;
+            })),
+            /*
+             * 7xkk - ADD Vx, byte
+             * Set Vx = Vx + kk.
+             * Adds the value kk to the value of register Vx, then stores the result in Vx. 
+             */
+            ("7xkk", Box::new(|(_, x, k1, k2), state| {
+                let kk = ((k1 << 4) + k2) as u8;
+                state.reg.V[x] = (Wrapping(state.reg.V[x]) + Wrapping(kk)).0;
+            })),
+            /*
+             * 8xy0 - LD Vx, Vy
+             * Set Vx = Vy.
+             * Stores the value of register Vy in register Vx.
+             */
+            ("8xy0", Box::new(|(_, x, y, _), state| {
+                state.reg.V[x] = state.reg.V[y];
             })),
             /*
              * 8xy1 - OR Vx, Vy
              * Set Vx = Vx OR Vy.
              * Performs a bitwise OR on the values of Vx and Vy, then stores the result in Vx. A bitwise OR compares the corrseponding bits from two values, and if either bit is 1, then the same bit in the result is also 1. Otherwise, it is 0. 
+             * With logic_resets_vf quirk VF is set to 0 afterwards, same for 8xy2 and 8xy3.
              */
             ("8xy1", Box::new(|(_, x, y, _), state| {
                 state.reg.V[x] |= state.reg.V[y];
+                if state.quirks.logic_resets_vf { state.reg.V[0xF] = 0; }
             })),
             /*
              * 8xy2 - AND Vx, Vy
@@ -275,6 +1525,7 @@ impl Inst{
              */
             ("8xy2", Box::new(|(_, x, y, _), state| {
                 state.reg.V[x] &= state.reg.V[y];
+                if state.quirks.logic_resets_vf { state.reg.V[0xF] = 0; }
             })),
             /*
              * 8xy3 - XOR Vx, Vy
@@ -283,15 +1534,18 @@ impl Inst{
              */
             ("8xy3", Box::new(|(_, x, y, _), state| {
                 state.reg.V[x] ^= state.reg.V[y];
+                if state.quirks.logic_resets_vf { state.reg.V[0xF] = 0; }
             })),
             /*
              * 8xy4 - ADD Vx, Vy
              * Set Vx = Vx + Vy, set VF = carry.
              * The values of Vx and Vy are added together. If the result is greater than 8 bits (i.e., > 255,) VF is set to 1, otherwise 0. Only the lowest 8 bits of the result are kept, and stored in Vx.
+             * Like in all of 8xy4-8xyE, flag is computed from the operands and VF written last, so VF holds the flag when x is F.
              */
             ("8xy4", Box::new(|(_, x, y, _), state| {
-                state.reg.V[0xF] = if (state.reg.V[x] as u16 + state.reg.V[y] as u16) > 255 {1} else {0};
+                let flag = if (state.reg.V[x] as u16 + state.reg.V[y] as u16) > 255 {1} else {0};
                 state.reg.V[x] = (Wrapping(state.reg.V[x]) + Wrapping(state.reg.V[y])).0;
+                state.reg.V[0xF] = flag;
             })),
             /*
              * 8xy5 - SUB Vx, Vy
@@ -299,17 +1553,20 @@ impl Inst{
              * If Vx > Vy, then VF is set to 1, otherwise 0. Then Vy is subtracted from Vx, and the results stored in Vx.
              */
             ("8xy5", Box::new(|(_, x, y, _), state| {
-                state.reg.V[0xF] = if state.reg.V[x] > state.reg.V[y] {1} else {0};
+                let flag = if state.reg.V[x] > state.reg.V[y] {1} else {0};
                 state.reg.V[x] = (Wrapping(state.reg.V[x]) - Wrapping(state.reg.V[y])).0;
+                state.reg.V[0xF] = flag;
             })),
             /*
              * 8xy6 - SHR Vx {, Vy}
              * Set Vx = Vx SHR 1.
              * If the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0. Then Vx is divided by 2.
+             * With shift_uses_vy quirk Vy is shifted instead and the result stored in Vx.
              */
-            ("8xy6", Box::new(|(_, x, _, _), state| {
-                state.reg.V[0xF] = if state.reg.V[x] & 0x01 != 0 {1} else {0};
-                state.reg.V[x] = state.reg.V[x] >> 1;
+            ("8xy6", Box::new(|(_, x, y, _), state| {
+                let src = if state.quirks.shift_uses_vy { state.reg.V[y] } else { state.reg.V[x] };
+                state.reg.V[x] = src >> 1;
+                state.reg.V[0xF] = src & 0x01;
             })),
             /*
              * 8xy7 - SUBN Vx, Vy
@@ -317,17 +1574,20 @@ impl Inst{
              * If Vy > Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the results stored in Vx.
              */
             ("8xy7", Box::new(|(_, x, y, _), state| {
-                state.reg.V[0xF] = if state.reg.V[y] > state.reg.V[x] {1} else {0};
+                let flag = if state.reg.V[y] > state.reg.V[x] {1} else {0};
                 state.reg.V[x] = (Wrapping(state.reg.V[y]) - Wrapping(state.reg.V[x])).0;
+                state.reg.V[0xF] = flag;
             })),
             /*
              * 8xyE - SHL Vx {, Vy}
              * Set Vx = Vx SHL 1.
              * If the most-significant bit of Vx is 1, then VF is set to 1, otherwise to 0. Then Vx is multiplied by 2.
+             * With shift_uses_vy quirk Vy is shifted instead and the result stored in Vx.
             */
-            ("8xyE", Box::new(|(_, x, _, _), state| {
-                state.reg.V[0xF] = if state.reg.V[x] & 0x80 != 0 {1} else {0};
-                state.reg.V[x] = state.reg.V[x] << 1;
+            ("8xyE", Box::new(|(_, x, y, _), state| {
+                let src = if state.quirks.shift_uses_vy { state.reg.V[y] } else { state.reg.V[x] };
+                state.reg.V[x] = src << 1;
+                state.reg.V[0xF] = src >> 7;
             })),
             /*
              * 9xy0 - SNE Vx, Vy
@@ -336,7 +1596,7 @@ impl Inst{
              */
             ("9xy0", Box::new(|(_, x, y, _), state| {
                 if state.reg.V[x] != state.reg.V[y] {
-                    state.reg.PC += 2;
+                    state.skip();
                 } 
             })),
             /*
@@ -352,10 +1612,12 @@ impl Inst{
              * Bnnn - JP V0, addr
              * Jump to location nnn + V0.
              * The program counter is set to nnn plus the value of V0.
+             * With jump_uses_vx quirk Vx is used instead of V0, where x is the highest nibble of nnn.
              */
             ("Bnnn", Box::new(|(_, a, b, c), state| {
                 let addr = ((a << 8) + (b << 4) + c) as u16;
-                state.reg.PC = addr + (state.reg.V[0] as u16);
+                let offset = if state.quirks.jump_uses_vx { state.reg.V[a] } else { state.reg.V[0] };
+                state.reg.PC = addr + (offset as u16);
             })),
             /*
              * Cxkk - RND Vx, byte
@@ -364,7 +1626,7 @@ impl Inst{
              */
             ("Cxkk", Box::new(|(_, x, k1, k2), state| {
                 let kk = ((k1 << 4) + k2) as u8;
-                let rnd = rand::thread_rng().gen::<u8>();
+                let rnd = state.rng.gen::<u8>();
                 state.reg.V[x] = rnd & kk;
             })),
             /*
@@ -372,23 +1634,54 @@ impl Inst{
              * Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
              * The interpreter reads n bytes from memory, starting at the address stored in I. These bytes are then displayed as sprites on screen at coordinates (Vx, Vy).
              * Sprites are XORed onto  the existing screen. If this causes any pixels to be erased, VF is set to 1, otherwise it is set to 0. If the sprite is positioned so part of it is outside the coordinates of the display, it wraps around to the opposite side of the screen.
+             * Starting coordinates are taken modulo display size first, Vx=70 draws at x=6.
+             * With no_wrapped_collision quirk only pixels drawn without wrapping set VF.
+             * With clip_sprites quirk pixels past the right or bottom edge are dropped, Vx=62 draws two columns.
+             * In grayscale mode every sprite row is 8 bytes, one intensity per pixel, XORed with Display::draw.
+             * With --draw-mode or sprites are ORed instead and VF stays 0.
              */
             ("Dxyn", Box::new(|(_, x, y, n), state| {
+                state.frame_draws += 1;
                 let addr = state.reg.I as usize;
-                let bytes = &state.mem[addr..addr+n];
-                
+                // Starting position wraps before drawing. Read before VF is cleared, it can hold a coordinate too
+                let (left, top) = (state.reg.V[x] as usize % state.display.width, state.reg.V[y] as usize % state.display.height);
+
                 state.reg.V[0xF] = 0;
 
-                let mut row = state.reg.V[y] as usize ;
-                for byte in bytes {  
-                    let mut mask = 0x80; 
-                    let mut col = state.reg.V[x] as usize;
-                    while mask != 0 {
-                        if state.display.pixel(row, col, byte & mask != 0) { state.reg.V[0xF] = 1; }
-                        mask = mask >> 1;
-                        col += 1;
+                if state.display.grayscale {
+                    let levels: Vec<u8> = (0..8 * n).map(|i| state.read_data(addr + i)).collect();
+                    for (i, level) in levels.into_iter().enumerate() {
+                        let (row, col) = (top + i / 8, left + i % 8);
+                        let wraps = state.display.wraps(row, col);
+                        if state.quirks.clip_sprites && wraps { continue; }
+                        let counts = !state.quirks.no_wrapped_collision || !wraps;
+                        if state.display.draw(row, col, level) && counts { state.reg.V[0xF] = 1; }
+                    }
+                } else {
+                    let bytes: Vec<u8> = (0..n).map(|i| state.read_data(addr + i)).collect();
+                    let mut row = top;
+                    for byte in bytes {  
+                        let mut mask = 0x80; 
+                        let mut col = left;
+                        while mask != 0 {
+                            let wraps = state.display.wraps(row, col);
+                            let counts = !state.quirks.no_wrapped_collision || !wraps;
+                            let drawn = !(state.quirks.clip_sprites && wraps);
+                            if drawn && state.display.pixel(row, col, byte & mask != 0) && counts { state.reg.V[0xF] = 1; }
+                            mask = mask >> 1;
+                            col += 1;
+                        }
+                        row += 1;
                     }
-                    row += 1;
+                }
+
+                state.draws += 1;
+                state.collisions += state.reg.V[0xF] as u64;
+                if state.diag.break_on_collision && state.reg.V[0xF] == 1 { state.diag.collision_at = Some(state.reg.PC - 2); }
+                if state.diag.log_draws {
+                    let wrapped = left + 8 > state.display.width || top + n > state.display.height;
+                    println!("{:#05X} DRW x={} y={} n={} I={:#05X} wrapped={} collision={}",
+                        state.reg.PC - 2, left, top, n, addr, wrapped, state.reg.V[0xF]);
                 }
             })),
             /*
@@ -397,8 +1690,8 @@ impl Inst{
              * Checks the keyboard, and if the key corresponding to the value of Vx is currently in the down position, PC is increased by 2.
              */
             ("Ex9E", Box::new(|(_, x, _, _), state| {
-                if state.key[state.reg.V[x] as usize] {
-                    state.reg.PC += 2;
+                if state.is_key_down(state.keypad_index(x)) {
+                    state.skip();
                 }
             })),
             /*
@@ -407,8 +1700,8 @@ impl Inst{
              * Checks the keyboard, and if the key corresponding to the value of Vx is currently in the up position, PC is increased by 2.
              */
             ("ExA1", Box::new(|(_, x, _, _), state| {
-                if !state.key[state.reg.V[x] as usize] {
-                    state.reg.PC += 2;
+                if !state.is_key_down(state.keypad_index(x)) {
+                    state.skip();
                 }
             })),
             /*
@@ -423,9 +1716,11 @@ impl Inst{
              * Fx0A - LD Vx, K
              * Wait for a key press, store the value of the key in Vx.
              * All execution stops until a key is pressed, then the value of that key is stored in Vx.
+             * Only a fresh press counts, a key held when the instruction starts has to be released first.
              */
             ("Fx0A", Box::new(|(_, x, _, _), state| {
                 state.awaiting_input = x as u8;
+                state.stale_keys = state.key;
             })),
             /*
              * Fx15 - LD DT, Vx
@@ -469,7 +1764,7 @@ impl Inst{
                 let start = state.reg.I as usize;
                 let mut num = state.reg.V[x];
                 for i in (0..3).rev() {
-                    state.mem[start + i] = num % 10;
+                    state.write_mem(start + i, num % 10);
                     num /= 10;
                 }
             })),
@@ -477,223 +1772,1751 @@ impl Inst{
              * Fx55 - LD [I], Vx
              * Store registers V0 through Vx in memory starting at location I.
              * The interpreter copies the values of registers V0 through Vx into memory, starting at the address in I.
+             * With load_store_increments_i quirk I is left pointing after the last written byte.
              */
             ("Fx55", Box::new(|(_, x, _, _), state| {
                 let start = state.reg.I as usize;
                 for i in 0..x+1 {
-                    state.mem[start + i] = state.reg.V[i];
+                    let val = state.reg.V[i];
+                    state.write_mem(start + i, val);
                 }
+                if state.quirks.load_store_increments_i { state.reg.I = state.reg.I.wrapping_add(x as u16 + 1); }
             })),
             /*
              * Fx65 - LD Vx, [I]
              * Read registers V0 through Vx from memory starting at location I.
              * The interpreter reads values from memory starting at location I into registers V0 through Vx.
+             * With load_store_increments_i quirk I is left pointing after the last read byte.
              */
             ("Fx65", Box::new(|(_, x, _, _), state| {
                 let start = state.reg.I as usize;
                 for i in 0..x+1 {
-                    state.reg.V[i] = state.mem[start + i];
+                    state.reg.V[i] = state.read_data(start + i);
                 }
+                if state.quirks.load_store_increments_i { state.reg.I = state.reg.I.wrapping_add(x as u16 + 1); }
+            })),
+            /*
+             * F000 NNNN - LD I, long addr (XO-CHIP)
+             * Set I = NNNN.
+             * The word following the instruction is loaded into I, execution continues after it.
+             */
+            ("F000", Box::new(|_, state| {
+                let pc = state.reg.PC as usize;
+                state.reg.I = ((state.read_mem(pc) as u16) << 8) | state.read_mem(pc + 1) as u16;
+                state.reg.PC = state.reg.PC.wrapping_add(2);
             })),
         ];
         
-        Inst { instructions: instset.into_iter().collect() }
+        Inst { instructions: instset.into_iter().collect(), lenient: false }
     }
 
     /*
-     * Fetches, decodes and executes opcode.
+     * Approximate COSMAC VIP cost of instruction in machine cycles, including ~40 cycle fetch/decode overhead.
+     *
+     *   00E0         3100   clears 256 bytes of display RAM
+     *   00EE, 1nnn   50
+     *   2nnn         66
+     *   3xkk-9xy0    50-54  skips, loads and adds
+     *   8xyN         84
+     *   Annn         52
+     *   Bnnn         62
+     *   Cxkk         76
+     *   Dxyn         rest of frame, DRW waits for display interrupt
+     *   Ex9E, ExA1   54
+     *   Fx07-Fx18    50
+     *   Fx1E         56
+     *   Fx29         60
+     *   Fx33         124
+     *   Fx55, Fx65   54 + 14 per register
      */
-    pub fn exec(&mut self, state: &mut State) {
+    fn cycles(key: &str, (_, x, _, _): ChunkedOp) -> u32 {
+        match key {
+            "00E0" => 3100,
+            "2nnn" => 66,
+            "5xy0" | "9xy0" | "Ex9E" | "ExA1" => 54,
+            "7xkk" => 50,
+            "8xy0" | "8xy1" | "8xy2" | "8xy3" | "8xy4" | "8xy5" | "8xy6" | "8xy7" | "8xyE" => 84,
+            "Annn" => 52,
+            "Bnnn" => 62,
+            "Cxkk" => 76,
+            "Dxyn" => FRAME_CYCLES,
+            "Fx1E" => 56,
+            "Fx29" => 60,
+            "Fx33" => 124,
+            "Fx55" | "Fx65" => 54 + 14 * (x as u32 + 1),
+            _ => 50,
+        }
+    }
+
+    /*
+     * Fetches, decodes and executes opcode. Returns its cost in machine cycles, see cycles.
+     */
+    pub fn exec(&mut self, state: &mut State) -> u32 {
         // Fetch
-        let (upper, lower) = (state.mem[state.reg.PC as usize] as u16, state.mem[state.reg.PC as usize+1] as u16);
-        let op = ((upper << 8) + lower) as usize;
-        state.reg.PC += 2;
+        let pc = state.reg.PC as usize;
+        let op = state.peek_opcode().unwrap_or_else(|| panic!("Fetch past end of memory at {:#05X}", pc)) as usize;
+        if state.diag.strict_alignment && !pc.is_multiple_of(2) {
+            panic!("Misaligned fetch of {:04X} at {:#05X}", op, pc);
+        }
+        if let Some(mut tracer) = state.diag.tracer.take() {
+            tracer.record(state);
+            state.diag.tracer = Some(tracer);
+        }
+        if state.diag.detect_smc {
+            let modified = state.written[pc] || state.written[pc + 1];
+            if modified && !state.diag.in_smc { println!("{:#05X} executing self-modified code", pc); }
+            state.diag.in_smc = modified;
+        }
+        // Last word of 64KB memory continues at 0
+        state.reg.PC = state.reg.PC.wrapping_add(2);
 
         // Decode
-        let bits = ((op >> 12) & 0xF, (op >> 8) & 0xF, (op >> 4) & 0xF, op & 0xF);
-        let key = match bits {
-            (0x0, 0x0, 0xE, 0x0) => "00E0",
-            (0x0, 0x0, 0xE, 0xE) => "00EE",
-            (0x1, _, _, _)       => "1nnn",
-            (0x2, _, _, _)       => "2nnn",
-            (0x3, _, _, _)       => "3xkk",
-            (0x4, _, _, _)       => "4xkk",
-            (0x5, _, _, 0x0)     => "5xy0",
-            (0x6, _, _, _)       => "6xkk",
-            (0x7, _, _, _)       => "7xkk",
-            (0x8, _, _, 0x0)     => "8xy0",
-            (0x8, _, _, 0x1)     => "8xy1",
-            (0x8, _, _, 0x2)     => "8xy2",
-            (0x8, _, _, 0x3)     => "8xy3",
-            (0x8, _, _, 0x4)     => "8xy4",
-            (0x8, _, _, 0x5)     => "8xy5",
-            (0x8, _, _, 0x6)     => "8xy6",
-            (0x8, _, _, 0x7)     => "8xy7",
-            (0x8, _, _, 0xE)     => "8xyE",
-            (0x9, _, _, 0x0)     => "9xy0",
-            (0xA, _, _, _)       => "Annn",
-            (0xB, _, _, _)       => "Bnnn",
-            (0xC, _, _, _)       => "Cxkk",
-            (0xD, _, _, _)       => "Dxyn",
-            (0xE, _, 0x9, 0xE)   => "Ex9E",
-            (0xE, _, 0xA, 0x1)   => "ExA1",
-            (0xF, _, 0x0, 0x7)   => "Fx07",
-            (0xF, _, 0x0, 0xA)   => "Fx0A",
-            (0xF, _, 0x1, 0x5)   => "Fx15",
-            (0xF, _, 0x1, 0x8)   => "Fx18",
-            (0xF, _, 0x1, 0xE)   => "Fx1E",
-            (0xF, _, 0x2, 0x9)   => "Fx29",
-            (0xF, _, 0x3, 0x3)   => "Fx33",
-            (0xF, _, 0x5, 0x5)   => "Fx55",
-            (0xF, _, 0x6, 0x5)   => "Fx65",
-            _ => panic!("Invalid insturction: {:?} | Hex: {:X}", bits, op),
-        };
+        let bits = nibbles(op as u16);
+        let key = decode(bits, self.lenient)
+            .unwrap_or_else(|| panic!("Invalid insturction: {:?} | Hex: {:X}", bits, op));
 
         // Execute
         let func = self.instructions.get_mut(key)
             .unwrap_or_else(|| panic!("Invalid insturction: {:?} | Hex: {:X}", bits, op));
         func(bits, state);
+        state.instructions += 1;
+        state.op_groups[bits.0] += 1;
+        Inst::cycles(key, bits)
+    }
+}
+
+pub fn nibbles(op: u16) -> ChunkedOp {
+    let op = op as usize;
+    ((op >> 12) & 0xF, (op >> 8) & 0xF, (op >> 4) & 0xF, op & 0xF)
+}
+
+/*
+ * Name of instruction for opcode split into nibbles, None if opcode is not a valid instruction.
+ * Lenient decoding accepts 5xyN and 9xyN with any N. XO-CHIP F000 is decoded in every mode.
+ */
+pub fn decode(bits: ChunkedOp, lenient: bool) -> Option<&'static str> {
+    let key = match bits {
+        (0x0, 0x0, 0xE, 0x0) => "00E0",
+        (0x0, 0x0, 0xE, 0xE) => "00EE",
+        (0x1, _, _, _)       => "1nnn",
+        (0x2, _, _, _)       => "2nnn",
+        (0x3, _, _, _)       => "3xkk",
+        (0x4, _, _, _)       => "4xkk",
+        (0x5, _, _, n) if n == 0 || lenient => "5xy0",
+        (0x6, _, _, _)       => "6xkk",
+        (0x7, _, _, _)       => "7xkk",
+        (0x8, _, _, 0x0)     => "8xy0",
+        (0x8, _, _, 0x1)     => "8xy1",
+        (0x8, _, _, 0x2)     => "8xy2",
+        (0x8, _, _, 0x3)     => "8xy3",
+        (0x8, _, _, 0x4)     => "8xy4",
+        (0x8, _, _, 0x5)     => "8xy5",
+        (0x8, _, _, 0x6)     => "8xy6",
+        (0x8, _, _, 0x7)     => "8xy7",
+        (0x8, _, _, 0xE)     => "8xyE",
+        (0x9, _, _, n) if n == 0 || lenient => "9xy0",
+        (0xA, _, _, _)       => "Annn",
+        (0xB, _, _, _)       => "Bnnn",
+        (0xC, _, _, _)       => "Cxkk",
+        (0xD, _, _, _)       => "Dxyn",
+        (0xE, _, 0x9, 0xE)   => "Ex9E",
+        (0xE, _, 0xA, 0x1)   => "ExA1",
+        (0xF, 0x0, 0x0, 0x0) => "F000",
+        (0xF, _, 0x0, 0x7)   => "Fx07",
+        (0xF, _, 0x0, 0xA)   => "Fx0A",
+        (0xF, _, 0x1, 0x5)   => "Fx15",
+        (0xF, _, 0x1, 0x8)   => "Fx18",
+        (0xF, _, 0x1, 0xE)   => "Fx1E",
+        (0xF, _, 0x2, 0x9)   => "Fx29",
+        (0xF, _, 0x3, 0x3)   => "Fx33",
+        (0xF, _, 0x5, 0x5)   => "Fx55",
+        (0xF, _, 0x6, 0x5)   => "Fx65",
+        _ => return None,
+    };
+    Some(key)
+}
+
+/*
+ * Decodes every aligned opcode of ROM loaded at entry, returning addresses and opcodes of undecodable ones.
+ */
+pub fn validate(bytes: &[u8], entry: u16, lenient: bool) -> Vec<(u16, u16)> {
+    disasm::opcodes(bytes, entry).into_iter()
+        .filter(|(_, op, _)| decode(nibbles(*op), lenient).is_none())
+        .map(|(addr, op, _)| (addr, op))
+        .collect()
+}
+
+/*
+ * Updates keybord map. Returns one of detected keys for awaiting input functionality.
+ */
+fn map_keyboard(state: &mut State, keymap: &Keymap, events: &mut sdl2::EventPump, pads: &Gamepads,
+                debounce: &mut Option<Debounce>, sticky: &mut Option<StickyKeys>) -> u8 {
+    let (keyboard, res) = read_keypad(keymap, events, pads, debounce, sticky);
+    for (i, pressed) in keyboard.iter().enumerate() { state.set_key(i, *pressed).unwrap(); }
+    res
+}
+
+/*
+ * Keypad keys held on physical keyboard or gamepads and the lowest of them, 0xFF if none.
+ */
+fn read_keypad(keymap: &Keymap, events: &mut sdl2::EventPump, pads: &Gamepads,
+               debounce: &mut Option<Debounce>, sticky: &mut Option<StickyKeys>) -> (Keyboard, u8) {
+    let mut res: u8 = 0xFF;
+    let keys: Vec<Keycode> = events.keyboard_state().pressed_scancodes()
+        .filter_map(Keycode::from_scancode).collect();
+    
+    let mut keyboard = [false; KEYBOARD_SIZE];
+    for keycode in keys {
+        if let Some(idx) = keymap.keypad_value(keycode) {
+            keyboard[idx] = true;
+        }
+    }
+    for idx in pads.held() {
+        keyboard[idx] = true;
+    }
+    if let Some(ref mut debounce) = debounce { keyboard = debounce.apply(keyboard); }
+    if let Some(ref mut sticky) = sticky { keyboard = sticky.apply(keyboard); }
+    if let Some(idx) = keyboard.iter().position(|down| *down) { res = idx as u8; }
+    (keyboard, res)
+}
+
+/*
+ * --sticky-keys: pressing a key toggles it, releasing it does nothing. Keeps physical state to find presses.
+ */
+struct StickyKeys {
+    physical: Keyboard,
+    latched: Keyboard,
+}
+impl StickyKeys {
+    fn new() -> Self {
+        StickyKeys { physical: [false; KEYBOARD_SIZE], latched: [false; KEYBOARD_SIZE] }
+    }
+
+    fn apply(&mut self, keyboard: Keyboard) -> Keyboard {
+        for (i, down) in keyboard.iter().enumerate() {
+            if *down && !self.physical[i] { self.latched[i] = !self.latched[i]; }
+        }
+        self.physical = keyboard;
+        self.latched
+    }
+}
+
+/*
+ * --key-debounce-ms: a key changes state only after staying down or up for threshold, timed from first read of the
+ * new state. Shorter blips are dropped.
+ */
+struct Debounce {
+    threshold: Duration,
+    stable: Keyboard,
+    // When key was first read differing from stable
+    since: [Option<Instant>; KEYBOARD_SIZE],
+}
+impl Debounce {
+    fn new(ms: u64) -> Self {
+        Debounce { threshold: Duration::from_millis(ms), stable: [false; KEYBOARD_SIZE], since: [None; KEYBOARD_SIZE] }
+    }
+
+    fn apply(&mut self, keyboard: Keyboard) -> Keyboard {
+        for (i, down) in keyboard.iter().enumerate() {
+            if *down == self.stable[i] {
+                self.since[i] = None;
+                continue;
+            }
+            match self.since[i] {
+                Some(since) if since.elapsed() >= self.threshold => { self.stable[i] = *down; self.since[i] = None; },
+                Some(_) => {},
+                None => self.since[i] = Some(Instant::now()),
+            }
+        }
+        self.stable
+    }
+}
+
+/*
+ * Feeds pressed key to Fx0A if it waits for one. With fx0a_on_release quirk the key is fed once it's released.
+ * Keys held since Fx0A began are skipped, of several freshly pressed keys the lowest one is taken.
+ */
+fn resolve_key_wait(state: &mut State, pressed: u8) {
+    if state.awaiting_input == 0xFF { return; }
+    for (stale, down) in state.stale_keys.iter_mut().zip(state.key.iter()) { *stale &= *down; }
+    let pressed = if pressed == 0xFF {
+        pressed
+    } else {
+        (0..KEYBOARD_SIZE).find(|&k| state.key[k] && !state.stale_keys[k]).map_or(0xFF, |k| k as u8)
+    };
+    let key = if !state.quirks.fx0a_on_release {
+        pressed
+    } else if state.held_key == 0xFF {
+        state.held_key = pressed;
+        0xFF
+    } else if !state.is_key_down(state.held_key as usize) {
+        std::mem::replace(&mut state.held_key, 0xFF)
+    } else {
+        0xFF
+    };
+    if key != 0xFF {
+        state.reg.V[state.awaiting_input as usize] = key;
+        state.awaiting_input = 0xFF;
+    }
+}
+
+/*
+ * Emulates single 60Hz frame: batch of instructions followed by timers update.
+ * With accurate timing the batch is limited by machine cycles instead of instruction count.
+ */
+fn run_frame(state: &mut State, inst: &mut Inst, opts: &Options, dbg: &mut Debugger) {
+    state.frame_draws = 0;
+    state.display.tick();
+    if opts.accurate_timing {
+        let mut budget = FRAME_CYCLES;
+        while budget > 0 && state.awaiting_input > 0xF {
+            if dbg.should_break(state.reg.PC) || draw_limit_reached(state, opts) || collision_trap(state, dbg) { break; }
+            budget = budget.saturating_sub(inst.exec(state));
+        }
+    } else {
+        for _ in 0..opts.instructions_per_frame {
+            if state.awaiting_input <= 0xF { break; }
+            // Display wait, rest of the frame is spent waiting for interrupt
+            if state.quirks.display_wait && state.frame_draws > 0 { break; }
+            if dbg.should_break(state.reg.PC) || draw_limit_reached(state, opts) || collision_trap(state, dbg) { break; }
+            inst.exec(state);
+        }
+    }
+    update_timers(state);
+}
+
+/*
+ * --break-on-collision, pauses after a Dxyn which set VF so the moment can be inspected with the overlay.
+ */
+fn collision_trap(state: &mut State, dbg: &mut Debugger) -> bool {
+    match state.diag.collision_at.take() {
+        Some(at) => {
+            println!("Collision at {:#05X}, paused", at);
+            dbg.paused = true;
+            true
+        },
+        None => false,
+    }
+}
+
+/*
+ * --max-draws-per-frame cap, reported every time it ends a frame early.
+ */
+fn draw_limit_reached(state: &State, opts: &Options) -> bool {
+    match opts.max_draws_per_frame {
+        Some(max) if state.frame_draws >= max => {
+            println!("Draw limit of {} per frame reached at {:#05X}, rest of frame skipped", max, state.reg.PC);
+            true
+        },
+        _ => false,
+    }
+}
+
+/*
+ * Nothing will change until input arrives: paused, or waiting for key or halted on a jump to itself with timers run
+ * out. Running timers keep the 60Hz pace, a beep started before Fx0A ends on time.
+ */
+fn is_idle(state: &State, dbg: &Debugger) -> bool {
+    if dbg.paused { return true; }
+    let halted = state.awaiting_input <= 0xF || state.peek_opcode() == Some(0x1000 | state.reg.PC);
+    halted && (state.diag.freeze_timers || state.reg.DT == 0 && state.reg.ST == 0)
+}
+
+/*
+ * Step over: CALL runs until it returns, anything else is a single step.
+ */
+fn step_over(state: &mut State, inst: &mut Inst, dbg: &mut Debugger) {
+    let pc = state.reg.PC;
+    if state.mem[pc as usize] >> 4 == 0x2 {
+        dbg.step_over(pc + 2);
+    } else if state.awaiting_input > 0xF {
+        inst.exec(state);
+    }
+}
+
+/*
+ * 60Hz timers tick. Like on hardware they keep running while Fx0A waits for a key.
+ */
+fn update_timers(state: &mut State) {
+    if !state.diag.freeze_timers {
+        state.reg.update_ST();
+        if state.reg.DT > 0 { state.dt_ticks += 1; }
+        state.reg.update_DT();
+    }
+}
+
+/*
+ * Prints display as text after every opts.ascii_frames frames, frame numbers counting from 1.
+ */
+fn log_ascii_frame(opts: &Options, frame: u64, display: &Display) {
+    if opts.ascii_frames == 0 || !frame.is_multiple_of(opts.ascii_frames) { return; }
+    println!("Frame {}", frame);
+    print!("{}", display);
+}
+
+/*
+ * Prints memory search results, addresses of first few matches only.
+ */
+fn print_matches(found: &[u16]) {
+    const SHOWN: usize = 32;
+    let addrs: Vec<String> = found.iter().take(SHOWN).map(|a| format!("{:03X}", a)).collect();
+    let more = if found.len() > SHOWN { format!(" and {} more", found.len() - SHOWN) } else { String::new() };
+    println!("{} matches: {}{}", found.len(), addrs.join(" "), more);
+}
+
+/*
+ * Cleanup once emulation ends, also when it stopped on an error or a fault. Anything that keeps open outputs should be
+ * finished here: trace file, replay recording, exported display.
+ */
+fn shutdown(state: &mut State, opts: &Options, recorder: Option<&Recorder>) -> Result<(), String> {
+    if opts.save_state_b64 { println!("State: {}", savestate::encode(&state.snapshot())); }
+    if let Some(ref path) = opts.export_svg {
+        match export_svg(path, &state.display, opts) {
+            Ok(()) => println!("Display written to {}", path),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    io::stdout().flush().ok();
+    let traced = state.diag.tracer.as_mut().map_or(Ok(()), |tracer| tracer.finish());
+    let recorded = recorder.map_or(Ok(()), |recorder| recorder.save());
+    traced.and(recorded)
+}
+
+/*
+ * Foreground and background of images written without window, theme colors with --bg-color replacing the background.
+ */
+fn export_colors(opts: &Options) -> Result<(Color, Color), String> {
+    let theme = THEMES.iter().find(|t| t.name == opts.theme).ok_or_else(|| format!("Unknown theme {}", opts.theme))?;
+    let bg = match opts.bg_color {
+        Some(ref color) => parse_color(color)?,
+        None => theme.bg,
+    };
+    Ok((theme.fg, bg))
+}
+
+/*
+ * Writes display as SVG in export_colors.
+ */
+fn export_svg(path: &str, display: &Display, opts: &Options) -> Result<(), String> {
+    let (fg, bg) = export_colors(opts)?;
+    fs::write(path, display.to_svg(opts.scale, fg, bg)).map_err(|e| format!("Unable to write {}: {}", path, e))
+}
+
+/*
+ * Runs without window or audio for given number of frames, as fast as possible. Returns exit code taken by
+ * --exit-from.
+ */
+fn run_headless(state: &mut State, inst: &mut Inst, opts: &Options, mut script: Option<InputScript>,
+                recorder: &mut Option<Recorder>) -> Result<Option<u8>, String> {
+    if opts.frames.is_none() && opts.max_time.is_none() {
+        return Err(String::from("--headless requires --frames or --max-time"));
+    }
+    let mut dbg = Debugger::default();
+    // Wall clock limit, whichever limit comes first stops the run
+    let deadline = opts.max_time.map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+    let mut dump = opts.frame_dump.as_ref().map(|dir| FrameDump::new(dir, opts.frame_dump_changed, &state.display)).transpose()?;
+    let colors = export_colors(opts)?;
+
+    let mut frame = 0;
+    while opts.frames.is_none_or(|frames| frame < frames) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            println!("Stopped after --max-time {}s", opts.max_time.unwrap());
+            break;
+        }
+        let keypad = state.keypad();
+        if let Some(ref mut script) = script {
+            let pressed = script.apply(frame, state);
+            resolve_key_wait(state, pressed);
+        }
+        if let Some(recorder) = recorder { recorder.record(frame, state); }
+        if !opts.step_on_input || state.keypad() != keypad { run_frame(state, inst, opts, &mut dbg); }
+        frame += 1;
+        log_ascii_frame(opts, frame, &state.display);
+        if let Some(ref mut dump) = dump { dump.record(&state.display, state.display.readraw, colors.0, colors.1)?; }
+        state.display.readraw = false;
+    }
+    println!("Ran {} frames, {} instructions", frame, state.instructions);
+    if opts.collision_stats {
+        println!("{} of {} draws collided, {:.2} collisions per frame", state.collisions, state.draws,
+            state.collisions as f64 / cmp::max(frame, 1) as f64);
+    }
+    match state.written_range() {
+        Some((lowest, highest)) => println!("Memory written from {:#05X} to {:#05X}", lowest, highest),
+        None => println!("No memory written"),
+    }
+    if opts.dump_state_json { println!("{}", state.to_json()); }
+    // Result byte of a test ROM
+    let code = match opts.exit_from {
+        Some(ExitFrom::Register(x)) => Some(state.reg.V[x]),
+        Some(ExitFrom::Memory(addr)) => Some(*state.mem.get(addr as usize)
+            .ok_or_else(|| format!("--exit-from address {:#05X} is past end of memory", addr))?),
+        None => None,
+    };
+    Ok(code)
+}
+
+/*
+ * Runs frames without window until the first Dxyn, so the window opens on the ROM's first picture. Gives up
+ * waiting for a key or after FAST_BOOT_FRAMES.
+ */
+fn fast_boot(state: &mut State, inst: &mut Inst, opts: &Options) {
+    let mut dbg = Debugger::default();
+    for frame in 0..FAST_BOOT_FRAMES {
+        run_frame(state, inst, opts, &mut dbg);
+        if state.frame_draws > 0 || state.awaiting_input <= 0xF {
+            println!("Skipped {} frames to first draw", frame + 1);
+            return;
+        }
+    }
+    println!("No draw in first {} frames", FAST_BOOT_FRAMES);
+}
+
+/*
+ * Runs headless as fast as possible, reporting emulation speed.
+ */
+fn run_bench(state: &mut State, inst: &mut Inst, opts: &Options, mut script: Option<InputScript>) -> Result<(), String> {
+    let frames = opts.frames.unwrap_or(BENCH_FRAMES);
+    let mut dbg = Debugger::default();
+    let started = Instant::now();
+
+    for frame in 0..frames {
+        if let Some(ref mut script) = script {
+            let pressed = script.apply(frame, state);
+            resolve_key_wait(state, pressed);
+        }
+        run_frame(state, inst, opts, &mut dbg);
     }
+
+    let secs = started.elapsed().as_secs_f64();
+    println!("Ran {} frames, {} instructions in {:.3}s", frames, state.instructions, secs);
+    println!("{:.0} IPS, {:.0} frames/s, {:.1}x real time", state.instructions as f64 / secs, frames as f64 / secs,
+        frames as f64 / 60.0 / secs);
+    Ok(())
 }
 
 /*
- * Updates keybord map. Returns one of detected keys for awaiting input functionality.
+ * Times each of BENCH_OPCODES OPCODE_BENCH_RUNS times and lists them slowest first. Times include fetch, decode
+ * and resetting PC, I and the stack before every run, so only differences between opcodes are meaningful.
+ * Ends with cost of decode and handler lookup alone, see bench_dispatch.
  */
-fn map_keyboard(keyboard: &mut Keyboard, events: &mut sdl2::EventPump) -> u8 {
-    let translation: HashMap<Keycode, usize> = vec![
-        Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
-        Keycode::Q, Keycode::W, Keycode::E, Keycode::R,
-        Keycode::A, Keycode::S, Keycode::D, Keycode::F,
-        Keycode::Z, Keycode::X, Keycode::C, Keycode::V,
-    ].into_iter().enumerate().map(|(i, key)| (key, i)).collect();
-
-    let mut res: u8 = 0xFF;
-    let keys: Vec<Keycode> = events.keyboard_state().pressed_scancodes()
-        .filter_map(Keycode::from_scancode).collect();
-    
-    for i in 0..keyboard.len() { keyboard[i] = false; }
-    for keycode in keys {
-        if let Some(idx) = translation.get(&keycode) {
-            keyboard[*idx] = true;
-            res = (*idx) as u8;
+fn bench_opcodes(state: &mut State, inst: &mut Inst) {
+    let mut results = Vec::new();
+    for op in BENCH_OPCODES.iter().cloned() {
+        state.mem[0x300] = (op >> 8) as u8;
+        state.mem[0x301] = (op & 0xFF) as u8;
+        let started = Instant::now();
+        for _ in 0..OPCODE_BENCH_RUNS {
+            state.reg.PC = 0x300;
+            state.reg.I = 0x400;
+            state.stack.clear();
+            // RET needs an address to return to
+            if op == 0x00EE { state.stack.push(0x300); }
+            state.reg.SP = state.stack.len() as u8;
+            state.awaiting_input = 0xFF;
+            inst.exec(state);
         }
+        let ns = started.elapsed().as_nanos() as f64 / OPCODE_BENCH_RUNS as f64;
+        results.push((op, ns));
     }
-    res
-}
+    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(cmp::Ordering::Equal));
 
-fn main() -> Result<(), String> {
-    if env::args().len() != 2 { panic!("Usage: {} [path]", env::args().nth(0).unwrap()); }
-
-    // Open File -> Read File -> Convert to vector of Opcodes
-    let filename = env::args().nth(1).unwrap();
-    let bytes = fs::read(path::Path::new(&filename))
-        .unwrap_or_else(|_| panic!("Unable to read {}", filename));
+    println!("Opcode  Instruction        ns/op");
+    for (op, ns) in results {
+        println!("{:04X}    {:<18} {:>6.1}", op, disasm::mnemonic(op, inst.lenient), ns);
+    }
+    bench_dispatch(inst);
+}
 
-    // Assemble all VM components
-    let mem = [0u8; RAM_SIZE];
-    let stack = [0u16; STACK_SIZE];
-    let reg = Reg::new();
-    let display = Display::new(DISPLAY_MODE_WIDTH, DISPLAY_MODE_HEIGHT);
-    let audio = Audio::new();
-    let key = [false; KEYBOARD_SIZE];
+/*
+ * Splits dispatch of exec into decode, a match on the nibbles, and the HashMap lookup of the handler by the decoded
+ * name. Handlers aren't called. Prints average nanoseconds per BENCH_OPCODES opcode of each.
+ */
+fn bench_dispatch(inst: &mut Inst) {
+    let (mut matched, mut hashed) = (Duration::default(), Duration::default());
+    for op in BENCH_OPCODES.iter().cloned() {
+        let bits = nibbles(op);
+        let started = Instant::now();
+        for _ in 0..OPCODE_BENCH_RUNS {
+            hint::black_box(decode(hint::black_box(bits), inst.lenient));
+        }
+        matched += started.elapsed();
+        let started = Instant::now();
+        for _ in 0..OPCODE_BENCH_RUNS {
+            let key = decode(hint::black_box(bits), inst.lenient);
+            hint::black_box(key.and_then(|key| inst.instructions.get_mut(key)).is_some());
+        }
+        hashed += started.elapsed();
+    }
+    let runs = (OPCODE_BENCH_RUNS as usize * BENCH_OPCODES.len()) as f64;
+    let decoded = matched.as_nanos() as f64 / runs;
+    println!("Dispatch: decode {:.1} ns/op, HashMap lookup {:.1} ns/op", decoded, (hashed.as_nanos() as f64 / runs - decoded).max(0.0));
+}
 
-    // And put them into State struct
-    let mut state = State {mem: mem, stack: stack, reg: reg, display: display, audio: audio, key: key, awaiting_input: 0xFF};
-
-    // Load bytes to memory
-    for (i, b) in bytes.into_iter().enumerate() { state.mem[ENTRY_POINT as usize + i] = b; }
-    state.reg.PC = ENTRY_POINT;
-
-    // Preload hex digits
-    let digits = vec![
-        0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-        0x20, 0x60, 0x20, 0x20, 0x70, // 1
-        0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-        0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-        0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-        0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-        0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-        0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-        0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-        0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-        0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-        0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-        0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-        0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-        0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-        0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-    ];
-    for (i, b) in digits.iter().enumerate() { state.mem[i] = *b; }
+/*
+ * Writes scale and theme changed with hotkeys to sidecar file of the ROM once the window closes, so its next run
+ * starts with them. Other settings and comments in the file are kept.
+ */
+fn persist_settings(rom_file: &str, opts: &Options, app: &App) -> Result<(), String> {
+    let theme = THEMES[app.theme].name;
+    if rom_file.is_empty() || app.scale == opts.scale && theme == opts.theme { return Ok(()); }
+    let path = format!("{}.toml", rom_file);
+    let text = fs::read_to_string(&path).unwrap_or_default();
+    let text = config::set(&text, "scale", &app.scale.to_string());
+    let text = config::set(&text, "theme", &format!("\"{}\"", theme));
+    fs::write(&path, text).map_err(|e| format!("Unable to write {}: {}", path, e))
+}
 
-    // Inst struct let's you execute instructions.
-    let mut inst = Inst::new();
+fn open_audio(opts: &Options) -> Audio {
+    let mut audio = Audio::new(Duration::from_millis(opts.beep_ramp_ms), opts.beep_wav.as_deref(), opts.pan);
+    if opts.dt_tick { audio.enable_tick(); }
+    audio
+}
 
+/*
+ * Window sized for display at scale, cleared to black. Event pump and canvas keep SDL alive.
+ */
+fn open_window(display: &Display, scale: u32) -> Result<(sdl2::EventPump, sdl2::render::Canvas<sdl2::video::Window>), String> {
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
 
-    let window = video_subsystem.window("Chip-8 emu", DISPLAY_SCALED_WIDTH as u32, DISPLAY_SCALED_HEIGHT as u32)
+    let (cols, rows) = display.area();
+    let (width, height) = (cols as u32 * scale, rows as u32 * scale);
+    let window = video_subsystem.window("Chip-8 emu", width, height)
         .position_centered().opengl()
         .build()
         .map_err(|e| e.to_string())?;
     
-    let mut events = sdl_context.event_pump()?;
+    let events = sdl_context.event_pump()?;
 
     let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
     canvas.present();
+    Ok((events, canvas))
+}
+
+fn run_window(state: &mut State, inst: &mut Inst, opts: &Options, mut script: Option<InputScript>,
+              recorder: &mut Option<Recorder>, mut rom: Option<Vec<u8>>, mut error: Option<String>) -> Result<(), String> {
+    let theme = THEMES.iter().position(|t| t.name == opts.theme)
+        .ok_or_else(|| format!("Unknown theme {}", opts.theme))?;
+    let keymap = load_keymap(opts)?;
+    for conflict in keymap.conflicts() {
+        eprintln!("Warning: {}", conflict);
+    }
+
+    let (mut events, mut canvas) = open_window(&state.display, opts.scale)?;
+    let pads = Gamepads::open(canvas.window().subsystem(), opts.controller_db.as_deref())?;
+    let mut sticky = if opts.sticky_keys { Some(StickyKeys::new()) } else { None };
+    let mut debounce = if opts.key_debounce_ms > 0 { Some(Debounce::new(opts.key_debounce_ms)) } else { None };
+    let texture_creator = canvas.texture_creator();
+    let mut native = if opts.gpu_scale { Some(NativeScreen::new(&texture_creator, &state.display)?) } else { None };
 
-    let h_strech = (DISPLAY_SCALED_WIDTH as f64)/(state.display.width as f64);
-    let v_strech = (DISPLAY_SCALED_HEIGHT as f64)/(state.display.height as f64);
+    let expected = match opts.expect_frame {
+        Some(ref path) => {
+            let text = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+            Some(state.display.parse_frame(&text).map_err(|e| format!("{}: {}", path, e))?)
+        },
+        None => None,
+    };
+
+    let transform = Transform::new(canvas.output_size()?, &state.display);
+    let profile = opts.profile.as_ref().and_then(|name| quirks::PROFILES.iter().position(|(n, _)| n == name));
+    let mut dbg = if opts.debug { Debugger::with_console() } else { Debugger::default() };
+    let background = opts.bg_color.as_ref().map(|c| parse_color(c)).transpose()?;
+    let border = opts.border_color.as_ref().map(|c| parse_color(c)).transpose()?;
+    let mut app = App { scale: opts.scale, theme: theme, transform: transform, shape: PixelShape::parse(&opts.pixel_shape)?, profile: profile, toast: None, inverted: false,
+        background: background, border: border };
+    // Sidecar settings are saved for, changes with dropped ROMs
+    let mut rom_file = opts.rom.clone();
+    let mut frame: u64 = 0;
+    let frame_time = Duration::new(0, (1000000000.0/60.0) as u32);
+    let started = Instant::now();
+    let mut last_tick = started;
+    let mut last_render = started;
+    let mut stats = if opts.show_stats { Some(FrameStats::new()) } else { None };
+    let mut histogram = if opts.opcode_histogram { Some(OpcodeHistogram::new(state)) } else { None };
+    let mut dump = opts.frame_dump.as_ref().map(|dir| FrameDump::new(dir, opts.frame_dump_changed, &state.display)).transpose()?;
+    let audio = open_audio(opts);
+    let mut beeping = false;
+    // Splash needs start delay to be shown in
+    let start_delay = if opts.splash { cmp::max(opts.start_delay, SPLASH_FRAMES) } else { opts.start_delay };
+    // Frames left before ROM starts running
+    let mut delay = start_delay;
+    // Debug overlay key is held down
+    let mut overlay = false;
 
     'emulating: loop {
         /*
          * INPUT
          */
-        for event in events.poll_iter() {
-            if let Event::Quit {..}  |  Event::KeyDown { keycode: Some(Keycode::Escape), .. } = event {
-                 break 'emulating; 
+        let mut resize = false;
+        // Instructions to execute while paused
+        let mut steps: u32 = 0;
+        let mut over = false;
+        for cmd in dbg.poll_console() {
+            match cmd {
+                Command::Break(addr)  => dbg.add_breakpoint(addr),
+                Command::Delete(addr) => dbg.remove_breakpoint(addr),
+                Command::Continue     => dbg.resume(),
+                Command::Pause        => dbg.paused = true,
+                Command::Step(n)      => steps = n,
+                Command::StepOver     => over = true,
+                Command::FreezeTimers => {
+                    state.diag.freeze_timers = !state.diag.freeze_timers;
+                    println!("Timers {}", if state.diag.freeze_timers { "frozen" } else { "running" });
+                },
+                Command::Search(value)      => print_matches(dbg.search(&state.mem, value)),
+                Command::SearchAgain(value) => print_matches(dbg.search_again(&state.mem, value)),
+                Command::Seed(seed)         => {
+                    state.reseed(seed);
+                    println!("Random numbers restarted from seed {}", seed);
+                },
             }
         }
-        let pressed = map_keyboard(&mut state.key, &mut events);
-        if pressed != 0xFF && state.awaiting_input != 0xFF {
-            state.reg.V[state.awaiting_input as usize] = pressed;
-            state.awaiting_input = 0xFF;
+        // Scripted input has to keep its frame pace
+        let idle = rom.is_none() || script.is_none() && !opts.max_speed && (opts.step_on_input || is_idle(state, &dbg));
+        let woken = if idle { events.wait_event_timeout(IDLE_WAIT_MS) } else { None };
+        let mut had_input = woken.is_some();
+        for event in woken.into_iter().chain(events.poll_iter()) {
+            had_input = true;
+            let control = match event {
+                Event::Quit {..} => break 'emulating,
+                Event::KeyDown { keycode: Some(key), .. } => keymap.control(key),
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    if keymap.control(key) == Some(Control::DebugOverlay) { overlay = false; }
+                    None
+                },
+                // Dropped ROM replaces the running one
+                Event::DropFile { filename, .. } => {
+                    match load_rom(&filename, opts.ram_size) {
+                        Ok(bytes) => {
+                            state.reset(&bytes);
+                            rom = Some(bytes);
+                            rom_file = filename.clone();
+                            delay = start_delay;
+                            error = None;
+                            let name = path::Path::new(&filename).file_name().map_or(filename.clone(), |n| n.to_string_lossy().into_owned());
+                            app.notify(format!("Loaded {}", name));
+                        },
+                        // Back to drop prompt, with error below it
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            state.reset(&[]);
+                            rom = None;
+                            error = Some(e);
+                        },
+                    }
+                    None
+                },
+                _ => None,
+            };
+            match control {
+                Some(Control::Quit)      => break 'emulating,
+                Some(Control::ScaleUp)   => resize |= app.set_scale(app.scale + 1),
+                Some(Control::ScaleDown) => resize |= app.set_scale(app.scale - 1),
+                Some(Control::Theme)     => app.next_theme(),
+                Some(Control::Invert)    => app.inverted = !app.inverted,
+                // Quirks only affect upcoming instructions, no reset needed
+                Some(Control::Profile)   => state.quirks = app.next_profile(),
+                Some(Control::Pause)     => dbg.toggle_pause(),
+                Some(Control::Step)      => steps = 1,
+                Some(Control::StepOver)  => over = true,
+                // Display only, CPU state and memory are left alone
+                Some(Control::ClearDisplay) => state.display.cls(),
+                Some(Control::DebugOverlay) => overlay = true,
+                Some(Control::Reset)     => if let Some(ref rom) = rom { state.reset(rom); delay = start_delay; },
+                Some(Control::DumpState) => println!("{}", state.to_json()),
+                // Live memory, so code the ROM generated or patched shows as it is now
+                Some(Control::Disassemble) => {
+                    for line in disasm::around_pc(&state.mem, &state.written, state.reg.PC, (8, 16), inst.lenient) {
+                        println!("{}", line);
+                    }
+                },
+                Some(Control::DumpMemory) => {
+                    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                    let dump = format!("memdump-{}.bin", secs);
+                    match state.dump_memory_to(path::Path::new(&dump)) {
+                        Ok(()) => println!("Memory dumped to {}", dump),
+                        Err(e) => eprintln!("Unable to dump memory to {}: {}", dump, e),
+                    }
+                },
+                None => {}
+            }
+        }
+        if resize {
+            let (cols, rows) = state.display.area();
+            let (width, height) = (cols as u32 * app.scale, rows as u32 * app.scale);
+            canvas.window_mut().set_size(width, height).map_err(|e| e.to_string())?;
+            app.transform = Transform::new(canvas.output_size()?, &state.display);
         }
+        // Script replaces live keypad, controls still work
+        let keypad = state.keypad();
+        let pressed = match script {
+            Some(ref mut script) => script.apply(frame, state),
+            None => map_keyboard(state, &keymap, &mut events, &pads, &mut debounce, &mut sticky),
+        };
+        resolve_key_wait(state, pressed);
+        if let (Some(recorder), Some(_)) = (recorder.as_mut(), &rom) { recorder.record(frame, state); }
 
         /*
          * UPDATE
+         * While paused only single instructions are executed on demand, timers stay frozen.
          */
-        for _ in 0..9 {
-            if state.awaiting_input <= 0xF { break; }
-            inst.exec(&mut state);
+        if rom.is_none() {
+            // Nothing to run until a ROM is dropped
+        } else if delay > 0 {
+            // Blank screen for a moment after load, frames aren't counted
+            delay -= 1;
+        } else if dbg.paused {
+            // Breakpoints interrupt multi-instruction step, except the one it starts from
+            for i in 0..steps {
+                if state.awaiting_input <= 0xF { break; }
+                if i > 0 && dbg.should_break(state.reg.PC) { break; }
+                inst.exec(state);
+            }
+            if over { step_over(state, inst, &mut dbg); }
+        } else if opts.step_on_input && state.keypad() == keypad {
+            // Frozen until a keypad key goes down or up, script keeps its pace
+            frame += 1;
+        } else if opts.max_speed {
+            // Unthrottled, timers still follow wall clock
+            // Batch is a rendered frame for the draw limit
+            state.frame_draws = 0;
+            for _ in 0..MAX_SPEED_BATCH {
+                if state.awaiting_input <= 0xF { break; }
+                if dbg.should_break(state.reg.PC) || draw_limit_reached(state, opts) || collision_trap(state, &mut dbg) { break; }
+                inst.exec(state);
+            }
+            while last_tick.elapsed() >= frame_time {
+                last_tick += frame_time;
+                state.display.tick();
+                update_timers(state);
+                frame += 1;
+                log_ascii_frame(opts, frame, &state.display);
+            }
+        } else {
+            run_frame(state, inst, opts, &mut dbg);
+            frame += 1;
+            log_ascii_frame(opts, frame, &state.display);
         }
-        if state.awaiting_input == 0xFF {
-            state.reg.update_ST(&state.audio);
-            state.reg.update_DT();
+        for event in state.audio_events() {
+            match event {
+                AudioEvent::Beep { on } => beeping = on,
+                AudioEvent::Tick => audio.tick(),
+            }
         }
+        // Paused or frozen ST would otherwise beep forever
+        if beeping && !dbg.paused && !state.diag.freeze_timers { audio.play(); } else { audio.stop(); }
 
         /*
          * RENDER
          */
-        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        if opts.max_speed {
+            if last_render.elapsed() < frame_time { continue; }
+            last_render = Instant::now();
+        }
+        if idle && !had_input && !state.display.readraw && app.toast.is_none() && stats.is_none() && histogram.is_none() { continue; }
+        canvas.set_draw_color(app.border());
         canvas.clear();
-        canvas.set_draw_color(Color::RGB(255, 255, 255));
-        for i in 0..state.display.height {
-            for j in 0..state.display.width {
-                let x = (j as f64) * h_strech;
-                let y = (i as f64) * v_strech;
-
-                if state.display.buffer[i][j] {
-                    let rect = Rect::new(x as i32, y as i32, h_strech as u32, v_strech as u32);
-                    canvas.fill_rect(rect)?;
+        if rom.is_none() {
+            // Prompt in the middle, load error below it
+            let (width, height) = canvas.output_size()?;
+            let line_height = (font::GLYPH_HEIGHT + 2) as i32 * 2;
+            let center = |text: &str| (width as i32 - font::text_size(text, 2).0 as i32) / 2;
+            let prompt = "Drop a ROM or pass a path";
+            let mut y = (height as i32 - line_height) / 2;
+            font::draw_text(&mut canvas, prompt, center(prompt), y, 2, app.fg())?;
+            if let Some(ref error) = error {
+                y += line_height;
+                for line in wrap_text(error, (width / (font::ADVANCE * 2)) as usize) {
+                    y += line_height;
+                    font::draw_text(&mut canvas, &line, center(&line), y, 2, Color::RGB(255, 80, 80))?;
+                }
+            }
+        } else {
+            draw_display(&mut canvas, &texture_creator, native.as_mut(), &state.display, &app)?;
+        }
+        // Project name over blank display while start delay runs
+        if opts.splash && delay > 0 && rom.is_some() {
+            let (width, height) = canvas.output_size()?;
+            let text = "CHIP-8";
+            let scale = cmp::max(width / 2 / font::text_size(text, 1).0, 1);
+            let (text_width, text_height) = font::text_size(text, scale);
+            font::draw_text(&mut canvas, text, (width - text_width) as i32 / 2, (height - text_height) as i32 / 2, scale, app.fg())?;
+        }
+        // Faint outline of every unlit cell, fifth of the way from background to foreground
+        if opts.show_grid && app.transform.cell >= 3 {
+            let (fg, bg) = (app.fg(), app.bg());
+            let faint = |f: u8, b: u8| (b as i32 + (f as i32 - b as i32) / 5) as u8;
+            canvas.set_draw_color(Color::RGB(faint(fg.r, bg.r), faint(fg.g, bg.g), faint(fg.b, bg.b)));
+            for i in 0..state.display.height {
+                for j in 0..state.display.width {
+                    if !state.display.lit(i, j) { canvas.draw_rect(app.transform.rect(i, j))?; }
+                }
+            }
+        }
+        // Mismatches against expected frame, bright when lit but shouldn't be, dark when missing
+        if let Some(ref expected) = expected {
+            for i in 0..state.display.height {
+                for j in 0..state.display.width {
+                    if !state.display.differs(expected, i, j) { continue; }
+                    let red = if state.display.buffer[i][j] != 0 { 255 } else { 110 };
+                    canvas.set_draw_color(Color::RGB(red, 0, 0));
+                    canvas.fill_rect(app.transform.rect(i, j))?;
                 }
             }
         }
+        if let Some((ref text, shown)) = app.toast {
+            if shown.elapsed() < TOAST_DURATION {
+                font::draw_label(&mut canvas, text, 0, 0, 2, app.fg(), app.bg())?;
+            } else {
+                app.toast = None;
+            }
+        }
+        if let Some(ref stats) = stats { stats.draw(&mut canvas, app.fg(), app.bg())?; }
+        if let Some(ref mut histogram) = histogram {
+            histogram.update(state);
+            histogram.draw(&mut canvas, app.fg(), app.bg())?;
+        }
+        if overlay && rom.is_some() { draw_debug_overlay(&mut canvas, state, dbg.paused, inst.lenient, sticky.is_some(), app.fg(), app.bg())?; }
+        canvas.present();
+        if let Some(ref mut dump) = dump { dump.record(&state.display, state.display.readraw, app.fg(), app.bg())?; }
+        state.display.readraw = false;
+        state.display.age();
+        if let Some(ref mut stats) = stats { stats.record(); }
+
+        if !opts.max_speed && !idle { thread::sleep(frame_time); }
+    }
+
+    if opts.max_speed {
+        let secs = started.elapsed().as_secs_f64();
+        println!("Executed {} instructions in {:.1}s ({:.0} IPS)", state.instructions, secs, state.instructions as f64 / secs);
+    }
+    audio.shutdown();
+    persist_settings(&rom_file, opts, &app)
+}
+
+/*
+ * THREADED MODE
+ * With --threaded the VM runs on its own thread at fixed 60Hz, the window thread only handles input, sound
+ * and rendering. Keypad and controls go to the VM, display snapshots and beeper changes come back.
+ */
+enum ToVm {
+    // Held keys and one of them, see map_keyboard
+    Keys(Keyboard, u8),
+    Reset,
+    Quit,
+}
+
+enum FromVm {
+    Frame(Display),
+    Audio(AudioEvent),
+}
+
+/*
+ * VM thread loop, runs until window is closed.
+ */
+fn run_vm(state: &mut State, opts: Options, rom: Vec<u8>, rx: mpsc::Receiver<ToVm>, tx: mpsc::Sender<FromVm>) {
+    let mut inst = Inst::new();
+    inst.lenient = opts.lenient_decode;
+    let mut dbg = Debugger::default();
+    let frame_time = Duration::new(0, (1000000000.0/60.0) as u32);
+    let mut next_tick = Instant::now();
+    let mut pressed = 0xFF;
+    let mut frame = 0;
+
+    loop {
+        for msg in rx.try_iter() {
+            match msg {
+                ToVm::Keys(keys, key) => {
+                    for (i, down) in keys.iter().enumerate() { state.set_key(i, *down).unwrap(); }
+                    pressed = key;
+                },
+                ToVm::Reset => state.reset(&rom),
+                ToVm::Quit => return,
+            }
+        }
+        resolve_key_wait(state, pressed);
+        run_frame(state, &mut inst, &opts, &mut dbg);
+        frame += 1;
+        log_ascii_frame(&opts, frame, &state.display);
+
+        let sent = state.audio_events().into_iter().try_for_each(|event| tx.send(FromVm::Audio(event)));
+        let sent = sent.and_then(|_| if state.display.readraw { tx.send(FromVm::Frame(state.display.clone())) } else { Ok(()) });
+        // Window is gone
+        if sent.is_err() { return; }
+        state.display.readraw = false;
+        state.display.age();
+
+        next_tick += frame_time;
+        let now = Instant::now();
+        if next_tick > now { thread::sleep(next_tick - now); } else { next_tick = now; }
+    }
+}
+
+fn run_threaded(state: State, opts: &Options, rom: Vec<u8>) -> Result<(), String> {
+    let theme = THEMES.iter().position(|t| t.name == opts.theme)
+        .ok_or_else(|| format!("Unknown theme {}", opts.theme))?;
+    let keymap = load_keymap(opts)?;
+    let (mut events, mut canvas) = open_window(&state.display, opts.scale)?;
+    let pads = Gamepads::open(canvas.window().subsystem(), opts.controller_db.as_deref())?;
+    let mut sticky = if opts.sticky_keys { Some(StickyKeys::new()) } else { None };
+    let mut debounce = if opts.key_debounce_ms > 0 { Some(Debounce::new(opts.key_debounce_ms)) } else { None };
+    let texture_creator = canvas.texture_creator();
+    let mut native = if opts.gpu_scale { Some(NativeScreen::new(&texture_creator, &state.display)?) } else { None };
+    let transform = Transform::new(canvas.output_size()?, &state.display);
+    let background = opts.bg_color.as_ref().map(|c| parse_color(c)).transpose()?;
+    let border = opts.border_color.as_ref().map(|c| parse_color(c)).transpose()?;
+    let mut app = App { scale: opts.scale, theme: theme, transform: transform, shape: PixelShape::parse(&opts.pixel_shape)?, profile: None, toast: None, inverted: false,
+        background: background, border: border };
+    let frame_time = Duration::new(0, (1000000000.0/60.0) as u32);
+
+    // Sound is played by the window thread
+    let audio = open_audio(opts);
+    let mut latest = state.display.clone();
+    let mut dump = opts.frame_dump.as_ref().map(|dir| FrameDump::new(dir, opts.frame_dump_changed, &latest)).transpose()?;
+    let (to_vm, vm_rx) = mpsc::channel();
+    let (vm_tx, from_vm) = mpsc::channel();
+    let vm_opts = opts.clone();
+    // State comes back even after a fault, so trace and histogram are still finished
+    let vm = thread::spawn(move || {
+        let mut state = state;
+        let ran = panic::catch_unwind(panic::AssertUnwindSafe(|| run_vm(&mut state, vm_opts, rom, vm_rx, vm_tx)));
+        (state, ran.is_ok())
+    });
+    let mut last_keys = ([false; KEYBOARD_SIZE], 0xFF);
+
+    'emulating: loop {
+        let mut resize = false;
+        for event in events.poll_iter() {
+            let control = match event {
+                Event::Quit {..} => break 'emulating,
+                Event::KeyDown { keycode: Some(key), .. } => keymap.control(key),
+                _ => None,
+            };
+            match control {
+                Some(Control::Quit)      => break 'emulating,
+                Some(Control::ScaleUp)   => resize |= app.set_scale(app.scale + 1),
+                Some(Control::ScaleDown) => resize |= app.set_scale(app.scale - 1),
+                Some(Control::Theme)     => app.next_theme(),
+                Some(Control::Invert)    => app.inverted = !app.inverted,
+                Some(Control::Reset)     => { to_vm.send(ToVm::Reset).ok(); },
+                // Everything else needs the state, which lives on the VM thread
+                Some(control)            => app.notify(format!("{} is not available with --threaded", keymap::control_name(control))),
+                None => {}
+            }
+        }
+        if resize {
+            let (cols, rows) = latest.area();
+            let (width, height) = (cols as u32 * app.scale, rows as u32 * app.scale);
+            canvas.window_mut().set_size(width, height).map_err(|e| e.to_string())?;
+            app.transform = Transform::new(canvas.output_size()?, &latest);
+        }
+        let keys = read_keypad(&keymap, &mut events, &pads, &mut debounce, &mut sticky);
+        if keys != last_keys {
+            to_vm.send(ToVm::Keys(keys.0, keys.1)).ok();
+            last_keys = keys;
+        }
+
+        // VM thread only sends frames it drew to
+        let mut changed = false;
+        loop {
+            match from_vm.try_recv() {
+                Ok(FromVm::Frame(display)) => { latest = display; changed = true; },
+                Ok(FromVm::Audio(AudioEvent::Beep { on: true })) => audio.play(),
+                Ok(FromVm::Audio(AudioEvent::Beep { on: false })) => audio.stop(),
+                Ok(FromVm::Audio(AudioEvent::Tick)) => audio.tick(),
+                Err(mpsc::TryRecvError::Empty) => break,
+                // VM thread stopped on a fault
+                Err(mpsc::TryRecvError::Disconnected) => break 'emulating,
+            }
+        }
+
+        canvas.set_draw_color(app.border());
+        canvas.clear();
+        draw_display(&mut canvas, &texture_creator, native.as_mut(), &latest, &app)?;
+        if let Some((ref text, shown)) = app.toast {
+            if shown.elapsed() < TOAST_DURATION {
+                font::draw_label(&mut canvas, text, 0, 0, 2, app.fg(), app.bg())?;
+            } else {
+                app.toast = None;
+            }
+        }
         canvas.present();
+        if let Some(ref mut dump) = dump { dump.record(&latest, changed, app.fg(), app.bg())?; }
+        thread::sleep(frame_time);
+    }
+
+    to_vm.send(ToVm::Quit).ok();
+    audio.shutdown();
+    let persisted = persist_settings(&opts.rom, opts, &app);
+    let (mut state, ran) = vm.join().map_err(|_| String::from("Emulation thread stopped on a fault"))?;
+    let finished = shutdown(&mut state, opts, None);
+    if !ran { return Err(String::from("Emulation thread stopped on a fault")); }
+    finished.and(persisted)
+}
+
+/*
+ * Splits text into lines of at most width characters, breaking at spaces where possible.
+ */
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.len() + 1 + word.len() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() { line.push(' '); }
+        line += word;
+        while line.len() > width && width > 0 {
+            let rest = line.split_off(width);
+            lines.push(std::mem::replace(&mut line, rest));
+        }
+    }
+    if !line.is_empty() { lines.push(line); }
+    lines
+}
+
+/*
+ * Reads ROM, making sure it fits in memory above entry point.
+ */
+fn load_rom(filename: &str, ram_size: usize) -> Result<Vec<u8>, String> {
+    let bytes = fs::read(path::Path::new(filename)).map_err(|e| format!("Unable to read {}: {}", filename, e))?;
+    if bytes.is_empty() {
+        return Err(format!("{} is empty", filename));
+    }
+    if bytes.len() > ram_size - ENTRY_POINT as usize {
+        return Err(format!("{} is too big to fit in memory", filename));
+    }
+    Ok(bytes)
+}
+
+fn load_keymap(opts: &Options) -> Result<Keymap, String> {
+    match opts.keymap {
+        Some(ref path) => Keymap::load(path),
+        None => Ok(Keymap::default()),
+    }
+}
+
+fn main() -> Result<(), String> {
+    let args: Vec<String> = env::args().collect();
+    let mut opts = Options::parse(args.clone())?;
+    // Per-ROM settings, command line still wins
+    let sidecar = format!("{}.toml", opts.rom);
+    if !opts.rom.is_empty() && path::Path::new(&sidecar).exists() {
+        opts = Options::from_sidecar(&sidecar)?.with_args(args)?;
+        println!("Using settings from {}", sidecar);
+    }
+    if opts.list_keys {
+        print!("{}", load_keymap(&opts)?.describe());
+        return Ok(());
+    }
+    let mut quirks = match opts.profile {
+        Some(ref name) => quirks::profile(name).ok_or_else(|| format!("Unknown profile {}", name))?,
+        None => Quirks::default(),
+    };
+    for spec in &opts.quirks {
+        quirks.apply(spec)?;
+    }
 
-        thread::sleep(Duration::new(0, (1000000000.0/60.0) as u32));
+    if opts.ram_size < RAM_SIZE || opts.ram_size > MAX_RAM_SIZE {
+        return Err(format!("--ram-size must be between {} and {}", RAM_SIZE, MAX_RAM_SIZE));
     }
+    // Open File -> Read File -> Convert to vector of Opcodes
+    let filename = opts.rom.clone();
+    // Window without ROM waits for one to be dropped, showing load error if there was one
+    let loaded = if filename.is_empty() { Ok(None) } else { load_rom(&filename, opts.ram_size).map(Some) };
+    let (bytes, error) = match loaded {
+        Ok(bytes) => (bytes, None),
+        Err(e) if !opts.headless && opts.command == Subcommand::Run => { eprintln!("{}", e); (None, Some(e)) },
+        Err(e) => return Err(e),
+    };
+    let rom: &[u8] = bytes.as_ref().map_or(&[], |b| b);
+    if opts.command == Subcommand::Info {
+        println!("Size:    {} bytes", rom.len());
+        println!("SHA-256: {}", sha256::hex_digest(rom));
+        match quirks::detect_variant(rom, ENTRY_POINT) {
+            Some(found) => {
+                let evidence: Vec<String> = found.evidence.iter().map(|(op, addr)| format!("{} at {:#05X}", op, addr)).collect();
+                println!("Variant: {} ({})", found.profile, evidence.join(", "));
+            },
+            None => println!("Variant: chip8, no SCHIP or XO-CHIP opcodes found"),
+        }
+        return Ok(());
+    }
+    // Profile quirks started from, if any
+    let mut selected = opts.profile.clone();
+    if let Some(found) = quirks::detect_variant(rom, ENTRY_POINT) {
+        let evidence: Vec<String> = found.evidence.iter().map(|(op, addr)| format!("{} at {:#05X}", op, addr)).collect();
+        println!("ROM looks like {} ({})", found.profile, evidence.join(", "));
+        // Explicit --profile always wins
+        if opts.auto_profile && opts.profile.is_none() {
+            quirks = quirks::profile(found.profile).unwrap();
+            selected = Some(found.profile.to_string());
+            println!("Applying {} profile", found.profile);
+        } else {
+            println!("Suggested: --profile {}", found.profile);
+        }
+    }
+    if let Some(warning) = quirks::mismatch_warning(rom, selected.as_deref()) {
+        eprintln!("Warning: {}", warning);
+    }
+    if opts.validate || opts.command == Subcommand::Validate {
+        let invalid = validate(rom, ENTRY_POINT, opts.lenient_decode);
+        for (addr, op) in &invalid {
+            println!("{:#05X}: {:04X} is not a valid instruction", addr, op);
+        }
+        println!("{} undecodable opcodes found", invalid.len());
+        if opts.command == Subcommand::Validate {
+            return if invalid.is_empty() { Ok(()) } else { Err(format!("{} is not a valid ROM", filename)) };
+        }
+    }
+    if opts.command == Subcommand::Disasm {
+        let lines = if opts.xrefs { disasm::xref_listing(rom, ENTRY_POINT, opts.lenient_decode) } else { disasm::listing(rom, ENTRY_POINT, opts.lenient_decode) };
+        for line in lines {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    // Replay brings its own settings and input, replacing whatever was given
+    let replay = match opts.play_replay {
+        Some(ref path) => Some(Replay::load(path)?),
+        None => None,
+    };
+    if let Some(ref replay) = replay {
+        if opts.input_script.is_some() { return Err(String::from("--play-replay can't be used with --input-script")); }
+        if let Some(warning) = replay.rom_warning(rom) { eprintln!("Warning: {}", warning); }
+        quirks = replay.settings.quirks;
+        opts.instructions_per_frame = replay.settings.instructions_per_frame;
+        opts.accurate_timing = replay.settings.accurate_timing;
+        opts.max_draws_per_frame = replay.settings.max_draws_per_frame;
+        opts.ram_size = replay.settings.ram_size;
+        opts.stack_depth = replay.settings.stack_depth;
+        opts.lenient_decode = replay.settings.lenient_decode;
+        opts.draw_mode = replay.settings.draw_mode.name().to_string();
+        if rom.len() > opts.ram_size - ENTRY_POINT as usize {
+            return Err(format!("{} is too big to fit in memory of replay", filename));
+        }
+        if opts.frames.is_none() { opts.frames = Some(replay.frames); }
+    }
+    // Debugger, scripts and replays work on the state, which the window thread doesn't have
+    if opts.threaded && (opts.debug || opts.max_speed || opts.step_on_input || opts.break_on_collision || opts.opcode_histogram
+        || opts.start_delay > 0 || opts.splash || opts.show_stats || opts.show_grid || opts.expect_frame.is_some()
+        || opts.input_script.is_some() || opts.record_replay.is_some() || replay.is_some()) {
+        return Err(String::from("--threaded can't be used with --debug, --max-speed, --step-on-input, --break-on-collision, --opcode-histogram, \
+            --start-delay, --splash, --show-stats, --show-grid, --expect-frame, input scripts or replays"));
+    }
+    // Skipped frames would shift scripted input
+    if opts.fast_boot && (opts.input_script.is_some() || opts.record_replay.is_some() || replay.is_some()) {
+        return Err(String::from("--fast-boot can't be used with input scripts or replays"));
+    }
+    // Wall clock paced frames can't be reproduced
+    if opts.max_speed && (opts.record_replay.is_some() || replay.is_some()) {
+        return Err(String::from("Replays can't be used with --max-speed"));
+    }
+    let seed = replay.as_ref().map(|r| r.settings.seed).or(opts.seed).unwrap_or_else(rand::random);
+    let draw_mode = DrawMode::parse(&opts.draw_mode)?;
+    let mut recorder = opts.record_replay.as_ref().map(|path| Recorder::new(path, Settings {
+        rom_hash: quirks::fnv1a(rom),
+        seed: seed,
+        quirks: quirks,
+        instructions_per_frame: opts.instructions_per_frame,
+        accurate_timing: opts.accurate_timing,
+        max_draws_per_frame: opts.max_draws_per_frame,
+        ram_size: opts.ram_size,
+        stack_depth: opts.stack_depth,
+        lenient_decode: opts.lenient_decode,
+        draw_mode: draw_mode,
+    }));
+    let script = match (replay, &opts.input_script) {
+        (Some(replay), _) => Some(replay.script),
+        (None, Some(path)) => Some(InputScript::load(path)?),
+        (None, None) => None,
+    };
 
+    // Assemble all VM components
+    let mem = vec![0u8; opts.ram_size];
+    if opts.stack_depth == 0 || opts.stack_depth > MAX_STACK_SIZE {
+        return Err(format!("--stack-depth must be between 1 and {}", MAX_STACK_SIZE));
+    }
+    let stack = Vec::with_capacity(opts.stack_depth);
+    let reg = Reg::new();
+    let mut display = Display::new(DISPLAY_MODE_WIDTH, DISPLAY_MODE_HEIGHT);
+    display.persist = opts.persist_frames;
+    display.grayscale = opts.grayscale;
+    display.smooth = opts.smooth_flicker;
+    display.draw_mode = draw_mode;
+    display.show_wrap = opts.show_wrap;
+    let key = [false; KEYBOARD_SIZE];
+
+    // And put them into State struct
+    let mut state = State {mem: mem, written: vec![false; opts.ram_size], initialized: vec![false; opts.ram_size], rom_len: 0, stack: stack, stack_depth: opts.stack_depth, reg: reg, display: display, vip_display: opts.vip_display, beeping: false, dt_ticks: 0, key: key, quirks: quirks, rng: StdRng::seed_from_u64(seed), seed: seed, awaiting_input: 0xFF, held_key: 0xFF, stale_keys: [false; KEYBOARD_SIZE], frame_draws: 0, instructions: 0, op_groups: [0; 16], draws: 0, collisions: 0, diag: Diagnostics::default()};
+    state.diag.log_draws = opts.log_draws;
+    state.diag.detect_smc = opts.detect_smc;
+    state.diag.strict_keys = opts.strict_keys;
+    state.diag.strict_alignment = opts.strict_alignment;
+    state.diag.warn_code_overwrite = opts.warn_code_overwrite;
+    state.diag.detect_uninit = opts.detect_uninit;
+    state.diag.break_on_collision = opts.break_on_collision;
+    if opts.trace.is_some() || opts.trace_compare.is_some() || opts.trace_first > 0 {
+        state.diag.tracer = Some(Tracer::new(opts.trace.as_ref(), opts.trace_compare.as_ref(), opts.trace_first)?);
+    }
+
+    // Load font and ROM
+    state.reset(rom);
+    if let Some(ref text) = opts.load_state_b64 {
+        state.restore(&savestate::decode(text).map_err(|e| format!("--load-state-b64: {}", e))?)?;
+    }
+
+    // Inst struct let's you execute instructions.
+    let mut inst = Inst::new();
+    inst.lenient = opts.lenient_decode;
+
+    if opts.command == Subcommand::Bench && opts.bench_opcodes {
+        bench_opcodes(&mut state, &mut inst);
+        return Ok(());
+    }
+    let windowed = opts.command != Subcommand::Bench && !opts.headless;
+    if windowed && opts.fast_boot && bytes.is_some() { fast_boot(&mut state, &mut inst, &opts); }
+    let bytes = match bytes {
+        // State lives on the VM thread, which shuts down on its own
+        Some(rom) if windowed && opts.threaded => return run_threaded(state, &opts, rom),
+        bytes => bytes,
+    };
+    // VM faults panic, caught so shutdown still completes the outputs before the panic goes on
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        if opts.command == Subcommand::Bench {
+            run_bench(&mut state, &mut inst, &opts, script).map(|_| None)
+        } else if opts.headless {
+            run_headless(&mut state, &mut inst, &opts, script, &mut recorder)
+        } else {
+            run_window(&mut state, &mut inst, &opts, script, &mut recorder, bytes, error).map(|_| None)
+        }
+    }));
+    // Also after an error, so outputs of the run so far are complete
+    let finished = shutdown(&mut state, &opts, recorder.as_ref());
+    let code = match result {
+        Ok(result) => result?,
+        Err(fault) => {
+            if let Err(e) = finished { eprintln!("{}", e); }
+            panic::resume_unwind(fault);
+        },
+    };
+    finished?;
+    if let Some(code) = code {
+        // process::exit skips destructors, state holds open outputs
+        drop(state);
+        println!("Exiting with {}", code);
+        std::process::exit(code as i32);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * State as main assembles it with default options, ROM loaded at entry point.
+     */
+    fn state(ops: &[u16]) -> State {
+        let rom: Vec<u8> = ops.iter().flat_map(|op| op.to_be_bytes().to_vec()).collect();
+        let mut state = State {mem: vec![0u8; RAM_SIZE], written: vec![false; RAM_SIZE], initialized: vec![false; RAM_SIZE], rom_len: 0,
+            stack: Vec::with_capacity(STACK_SIZE), stack_depth: STACK_SIZE, reg: Reg::new(),
+            display: Display::new(DISPLAY_MODE_WIDTH, DISPLAY_MODE_HEIGHT), vip_display: false, beeping: false, dt_ticks: 0,
+            key: [false; KEYBOARD_SIZE], quirks: Quirks::default(), rng: StdRng::seed_from_u64(0), seed: 0, awaiting_input: 0xFF,
+            held_key: 0xFF, stale_keys: [false; KEYBOARD_SIZE], frame_draws: 0, instructions: 0, op_groups: [0; 16], draws: 0,
+            collisions: 0, diag: Diagnostics::default()};
+        state.reset(&rom);
+        state
+    }
+
+    fn run(state: &mut State, count: usize) {
+        let mut inst = Inst::new();
+        for _ in 0..count { inst.exec(state); }
+    }
+
+    /*
+     * Runs op with VF and V1 set, x being F so the flag and the result both go to VF.
+     */
+    fn flag_of(op: u16, vf: u8, v1: u8) -> u8 {
+        let mut state = state(&[0x6F00 | vf as u16, 0x6100 | v1 as u16, op]);
+        run(&mut state, 3);
+        state.reg.V[0xF]
+    }
+
+    #[test]
+    fn add_flag_wins_over_vf_result() {
+        assert_eq!(flag_of(0x8F14, 0xF0, 0x20), 1);
+        assert_eq!(flag_of(0x8F14, 0x10, 0x20), 0);
+    }
+
+    #[test]
+    fn sub_flag_wins_over_vf_result() {
+        assert_eq!(flag_of(0x8F15, 0x30, 0x10), 1);
+        assert_eq!(flag_of(0x8F15, 0x10, 0x30), 0);
+    }
+
+    #[test]
+    fn shr_flag_wins_over_vf_result() {
+        assert_eq!(flag_of(0x8F16, 0x05, 0), 1);
+        assert_eq!(flag_of(0x8F16, 0x04, 0), 0);
+    }
+
+    #[test]
+    fn subn_flag_wins_over_vf_result() {
+        assert_eq!(flag_of(0x8F17, 0x10, 0x30), 1);
+        assert_eq!(flag_of(0x8F17, 0x30, 0x10), 0);
+    }
+
+    #[test]
+    fn shl_flag_wins_over_vf_result() {
+        assert_eq!(flag_of(0x8F1E, 0x81, 0), 1);
+        assert_eq!(flag_of(0x8F1E, 0x41, 0), 0);
+    }
+
+    #[test]
+    fn packed_bits_round_trip() {
+        // 7x5 leaves 3 unused bits in the last byte
+        let mut display = Display::new(7, 5);
+        for (row, col) in &[(0, 0), (0, 6), (2, 3), (4, 0), (4, 6)] { display.pixel(*row, *col, true); }
+        let bits = display.packed_bits();
+        assert_eq!(bits.len(), 5);
+        let unpacked = Display::from_packed_bits(7, 5, &bits).unwrap();
+        assert_eq!(unpacked.to_string(), display.to_string());
+        assert_eq!(unpacked.packed_bits(), bits);
+    }
+
+    #[test]
+    fn key_tests_mask_vx_above_f() {
+        // V0=FF tests key F, held for SKP and released for SKNP
+        let mut state = state(&[0x60FF, 0xE09E, 0x0000, 0xE0A1]);
+        state.set_key(0xF, true).unwrap();
+        run(&mut state, 2);
+        assert_eq!(state.reg.PC, 0x206);
+        state.set_key(0xF, false).unwrap();
+        run(&mut state, 1);
+        assert_eq!(state.reg.PC, 0x20A);
+    }
+
+    #[test]
+    fn font_and_bcd_accept_vx_ff() {
+        let mut state = state(&[0x60FF, 0xF029, 0xA300, 0xF033]);
+        run(&mut state, 2);
+        assert_eq!(state.reg.I, 0xFF * 5);
+        run(&mut state, 2);
+        assert_eq!(&state.mem[0x300..0x303], &[2, 5, 5]);
+    }
+
+    /*
+     * VF after op on V0=0C and V1=0A, VF starting at 5.
+     */
+    fn logic_vf(op: u16, resets: bool) -> u8 {
+        let mut state = state(&[0x600C, 0x610A, 0x6F05, op]);
+        state.quirks.logic_resets_vf = resets;
+        run(&mut state, 4);
+        state.reg.V[0xF]
+    }
+
+    #[test]
+    fn logic_keeps_vf_without_quirk() {
+        for op in &[0x8011, 0x8012, 0x8013] { assert_eq!(logic_vf(*op, false), 5); }
+    }
+
+    #[test]
+    fn logic_resets_vf_with_quirk() {
+        for op in &[0x8011, 0x8012, 0x8013] { assert_eq!(logic_vf(*op, true), 0); }
+        let mut state = state(&[0x600C, 0x610A, 0x8011]);
+        state.quirks.logic_resets_vf = true;
+        run(&mut state, 3);
+        assert_eq!(state.reg.V[0], 0x0E);
+    }
+
+    #[test]
+    fn key_wait_takes_lowest_of_simultaneous_keys() {
+        let mut state = state(&[0xF50A]);
+        run(&mut state, 1);
+        state.set_key(7, true).unwrap();
+        state.set_key(3, true).unwrap();
+        let pressed = state.pressed_key();
+        resolve_key_wait(&mut state, pressed);
+        assert_eq!(state.awaiting_input, 0xFF);
+        assert_eq!(state.reg.V[5], 3);
+    }
+
+    #[test]
+    fn key_wait_ignores_key_held_before_it() {
+        let mut state = state(&[0xF50A]);
+        state.set_key(4, true).unwrap();
+        run(&mut state, 1);
+        let pressed = state.pressed_key();
+        resolve_key_wait(&mut state, pressed);
+        assert_eq!(state.awaiting_input, 5);
+        state.release(4).unwrap();
+        assert_eq!(state.awaiting_input, 5);
+        state.press(4).unwrap();
+        assert_eq!(state.awaiting_input, 0xFF);
+        assert_eq!(state.reg.V[5], 4);
+    }
+
+    /*
+     * VF of two pixel sprite drawn at x=63, its second pixel wrapping to x=0, over lit pixel at col.
+     */
+    fn straddling_collision(col: usize, no_wrapped_collision: bool) -> u8 {
+        let mut state = state(&[0xA300, 0x603F, 0x6100, 0xD011]);
+        state.mem[0x300] = 0xC0;
+        state.quirks.no_wrapped_collision = no_wrapped_collision;
+        state.display.pixel(0, col, true);
+        run(&mut state, 4);
+        assert!(state.display.lit(0, 0) != (col == 0) && state.display.lit(0, 63) != (col == 63));
+        state.reg.V[0xF]
+    }
+
+    #[test]
+    fn straddling_sprite_collides_on_both_sides() {
+        assert_eq!(straddling_collision(63, false), 1);
+        assert_eq!(straddling_collision(0, false), 1);
+    }
+
+    #[test]
+    fn straddling_sprite_ignores_wrapped_collision_with_quirk() {
+        assert_eq!(straddling_collision(63, true), 1);
+        assert_eq!(straddling_collision(0, true), 0);
+    }
+
+    /*
+     * Lit columns of row 0 after drawing 8 pixel wide sprite at Vx=x.
+     */
+    fn sprite_columns(x: u8, clip_sprites: bool) -> Vec<usize> {
+        let mut state = state(&[0xA300, 0x6000 | x as u16, 0x6100, 0xD011]);
+        state.mem[0x300] = 0xFF;
+        state.quirks.clip_sprites = clip_sprites;
+        run(&mut state, 4);
+        (0..DISPLAY_MODE_WIDTH).filter(|&col| state.display.lit(0, col)).collect()
+    }
+
+    #[test]
+    fn sprite_past_right_edge_clips_with_quirk() {
+        assert_eq!(sprite_columns(62, true), vec![62, 63]);
+        assert_eq!(sprite_columns(62, false), vec![0, 1, 2, 3, 4, 5, 62, 63]);
+    }
+
+    #[test]
+    fn sprite_start_wraps_before_clipping() {
+        assert_eq!(sprite_columns(70, true), (6..14).collect::<Vec<_>>());
+        assert_eq!(sprite_columns(70, false), (6..14).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn load_near_top_of_ram_wraps() {
+        let mut state = state(&[0xAFFE, 0xF365]);
+        state.mem[0xFFE] = 0x12;
+        state.mem[0xFFF] = 0x34;
+        state.quirks.load_store_increments_i = true;
+        run(&mut state, 2);
+        // Last two bytes come from the font at 0
+        assert_eq!(&state.reg.V[..4], &[0x12, 0x34, FONT[0], FONT[1]]);
+        assert_eq!(state.reg.I, 0x1002);
+    }
+
+    #[test]
+    fn store_near_top_of_ram_wraps() {
+        let mut state = state(&[0x60AA, 0x61BB, 0x62CC, 0xAFFF, 0xF255]);
+        state.quirks.load_store_increments_i = true;
+        run(&mut state, 5);
+        assert_eq!((state.mem[0xFFF], state.mem[0], state.mem[1]), (0xAA, 0xBB, 0xCC));
+        assert_eq!(state.reg.I, 0x1002);
+    }
+
+    #[test]
+    fn store_at_top_of_i_range_wraps_i() {
+        let mut state = state(&[0xF755]);
+        state.quirks.load_store_increments_i = true;
+        state.reg.I = 0xFFF8;
+        run(&mut state, 1);
+        assert_eq!(state.reg.I, 0x0000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Memory read out of bounds")]
+    fn load_near_top_of_ram_stops_without_wrapping() {
+        let mut state = state(&[0xAFFE, 0xF365]);
+        state.quirks.wrap_i_addressing = false;
+        run(&mut state, 2);
+    }
+
+    #[test]
+    fn rgba_buffer_scales_pixels() {
+        let mut display = Display::new(2, 1);
+        display.pixel(0, 1, true);
+        let (fg, bg) = (Color::RGB(1, 2, 3), Color::RGB(9, 8, 7));
+        let (on, off) = ([1, 2, 3, 255], [9, 8, 7, 255]);
+        let row: Vec<u8> = [off, off, on, on].concat();
+        assert_eq!(display.rgba_buffer(2, fg, bg), [row.clone(), row].concat());
+        assert_eq!(display.rgba_buffer(1, fg, bg), [off, on].concat());
+    }
+
+    /*
+     * Dxyn executed in each of the first frames of a ROM drawing in a loop.
+     */
+    fn draws_per_frame(display_wait: bool) -> Vec<u64> {
+        let mut state = state(&[0xD011, 0x1200]);
+        state.quirks.display_wait = display_wait;
+        let (mut inst, opts, mut dbg) = (Inst::new(), Options::default(), Debugger::default());
+        (0..3).map(|_| {
+            let before = state.draws;
+            run_frame(&mut state, &mut inst, &opts, &mut dbg);
+            state.draws - before
+        }).collect()
+    }
+
+    #[test]
+    fn display_wait_draws_once_per_tick() {
+        assert_eq!(draws_per_frame(true), vec![1, 1, 1]);
+        assert_eq!(draws_per_frame(false), vec![5, 4, 5]);
+    }
+
+    #[test]
+    fn peek_leaves_pc_alone() {
+        let mut state = state(&[0x6A02, 0x00E0]);
+        assert_eq!(state.peek_opcode(), Some(0x6A02));
+        assert_eq!(state.peek_decoded(false).unwrap(), disasm::mnemonic(0x6A02, false));
+        assert_eq!((state.reg.PC, state.reg.V[0xA]), (0x200, 0));
+        run(&mut state, 1);
+        assert_eq!(state.peek_opcode(), Some(0x00E0));
+        assert_eq!(state.reg.PC, 0x202);
+        state.reg.PC = RAM_SIZE as u16 - 1;
+        assert_eq!(state.peek_opcode(), None);
+        assert_eq!(state.peek_decoded(false), None);
+    }
+
+    #[test]
+    fn long_load_address_is_not_an_instruction() {
+        // FFFF alone isn't a valid opcode
+        let rom = [0xF0, 0x00, 0xFF, 0xFF, 0x00, 0xE0, 0xFF, 0xFF];
+        assert_eq!(validate(&rom, ENTRY_POINT, false), vec![(0x206, 0xFFFF)]);
+        let listing = disasm::listing(&rom, ENTRY_POINT, false);
+        assert_eq!(listing[0], "200: F000  LD I, FFFF");
+        assert_eq!(listing[1], "204: 00E0  CLS");
+        assert_eq!(listing.len(), 3);
+    }
+
+    #[test]
+    fn long_load_sets_i() {
+        let mut state = state(&[0xF000, 0x1234, 0x6001]);
+        run(&mut state, 2);
+        assert_eq!((state.reg.I, state.reg.V[0]), (0x1234, 1));
+    }
+
+    #[test]
+    fn mismatch_warning_only_for_other_profile() {
+        let breakout = include_bytes!("../ch8/breakout.ch8");
+        assert_eq!(quirks::mismatch_warning(breakout, None), None);
+        assert_eq!(quirks::mismatch_warning(breakout, Some("chip8")), None);
+        assert!(quirks::mismatch_warning(breakout, Some("schip")).unwrap().contains("--profile chip8"));
+        assert_eq!(quirks::mismatch_warning(&[0x12, 0x00], Some("schip")), None);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut state = state(&[0x2206, 0x0000, 0x0000, 0x6A42, 0xA300, 0xD015]);
+        run(&mut state, 4);
+        let text = savestate::encode(&state.snapshot());
+        let mut restored = self::state(&[]);
+        restored.restore(&savestate::decode(&text).unwrap()).unwrap();
+        assert_eq!(restored.to_json(), state.to_json());
+    }
+
+    #[test]
+    fn save_state_rejects_bad_pc_and_stack_pointer() {
+        let state = state(&[0x2204]);
+        let mut snapshot = state.snapshot();
+        snapshot.pc = RAM_SIZE as u16 - 1;
+        assert!(self::state(&[]).restore(&snapshot).unwrap_err().contains("PC"));
+        let mut snapshot = state.snapshot();
+        snapshot.sp = 1;
+        assert!(self::state(&[]).restore(&snapshot).unwrap_err().contains("stack pointer"));
+        assert!(savestate::decode("not base64!").is_err());
+    }
+
+    #[test]
+    fn shutdown_finishes_trace_file() {
+        let path = env::temp_dir().join(format!("chip8-trace-{}.txt", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let mut state = state(&[0x6A02, 0x6B03]);
+        state.diag.tracer = Some(Tracer::new(Some(&path), None, 0).unwrap());
+        run(&mut state, 2);
+        shutdown(&mut state, &Options::default(), None).unwrap();
+        let trace = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(trace.lines().count(), 2);
+        assert!(trace.starts_with("0200 6A02 "));
+    }
+
+    #[test]
+    fn replay_round_trip_keeps_settings() {
+        let path = env::temp_dir().join(format!("chip8-replay-{}.txt", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let settings = Settings { rom_hash: 0x1234, seed: 7, quirks: Quirks::default(), instructions_per_frame: 11,
+            accurate_timing: true, max_draws_per_frame: Some(3), ram_size: 0x2000, stack_depth: 32, lenient_decode: true,
+            draw_mode: DrawMode::Or };
+        Recorder::new(&path, settings).save().unwrap();
+        let loaded = Replay::load(&path).unwrap().settings;
+        fs::remove_file(&path).ok();
+        assert_eq!(loaded.max_draws_per_frame, Some(3));
+        assert_eq!((loaded.ram_size, loaded.stack_depth), (0x2000, 32));
+        assert!(loaded.lenient_decode);
+        assert_eq!(loaded.draw_mode, DrawMode::Or);
+    }
+
+    #[test]
+    fn replay_without_new_settings_uses_defaults() {
+        let path = env::temp_dir().join(format!("chip8-old-replay-{}.txt", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        fs::write(&path, "rom = 0\nseed = 1\nipf = 9\naccurate_timing = off\nframes = 10\n[input]\n").unwrap();
+        let loaded = Replay::load(&path).unwrap().settings;
+        fs::remove_file(&path).ok();
+        assert_eq!(loaded.max_draws_per_frame, None);
+        assert_eq!((loaded.ram_size, loaded.stack_depth), (RAM_SIZE, STACK_SIZE));
+        assert!(!loaded.lenient_decode);
+        assert_eq!(loaded.draw_mode, DrawMode::Xor);
+    }
+
+    #[test]
+    fn key_wait_with_running_timers_is_not_idle() {
+        let mut state = state(&[0x6A03, 0xFA15, 0xFA18, 0xF00A]);
+        let dbg = Debugger::default();
+        run(&mut state, 4);
+        assert!(state.awaiting_input <= 0xF);
+        assert!(!is_idle(&state, &dbg));
+        // Timers keep ticking once per frame while the key wait holds the CPU
+        let mut inst = Inst::new();
+        let mut dbg = Debugger::default();
+        for _ in 0..3 { run_frame(&mut state, &mut inst, &Options::default(), &mut dbg); }
+        assert_eq!((state.reg.DT, state.reg.ST), (0, 0));
+        assert!(is_idle(&state, &dbg));
+    }
+
+    #[test]
+    fn pc_wraps_at_top_of_64k_memory() {
+        let mut state = state(&[]);
+        state.mem = vec![0u8; MAX_RAM_SIZE];
+        state.written = vec![false; MAX_RAM_SIZE];
+        state.initialized = vec![false; MAX_RAM_SIZE];
+        state.mem[0xFFFC..].copy_from_slice(&[0x30, 0x00, 0x60, 0x01]);
+        state.reg.PC = 0xFFFC;
+        run(&mut state, 1);
+        assert_eq!(state.reg.PC, 0x0000);
+        state.reg.PC = 0xFFFE;
+        run(&mut state, 1);
+        assert_eq!((state.reg.PC, state.reg.V[0]), (0x0000, 1));
+    }
+
+    #[test]
+    fn config_set_replaces_top_level_key_and_keeps_sections() {
+        let text = "# mine\nscale = 10\n[quirks]\nscale = on\n";
+        assert_eq!(config::set(text, "scale", "12"), "# mine\nscale = 12\n[quirks]\nscale = on\n");
+        assert_eq!(config::set(text, "theme", "\"amber\""), "# mine\nscale = 10\ntheme = \"amber\"\n[quirks]\nscale = on\n");
+        assert_eq!(config::set("", "scale", "3"), "scale = 3\n");
+    }
+}
@@ -0,0 +1,481 @@
+/*
+ * OPTIONS
+ * Command line parsing. Every flag has a sensible default, ROM path is only required by headless run and other
+ * commands. Arguments in the CHIP8_ARGS environment variable are applied before the command line ones.
+ */
+use std::{env, fs};
+
+use crate::config;
+use crate::debugger::parse_addr;
+
+pub const USAGE: &str = "Usage: chip8 [command] [options] [path]
+
+Commands:
+    run                 Run ROM in window, or headless with --headless (default)
+    disasm              Print disassembly of ROM
+    validate            Report opcodes which can't be decoded, fail if there are any
+    bench               Run ROM headless and report speed, --frames defaults to 3600,
+                        with --opcodes time every instruction on its own, no ROM needed
+    info                Print ROM size, SHA-256 and variant opcodes
+
+Options:
+    --scale <n>         Integer window scale (default 10)
+    --theme <name>      Color theme: white, amber, green, blue (default white)
+    --bg-color <hex>    Color of unlit pixels in place of theme background, e.g. 102030
+    --border-color <hex>
+                        Color around display when window doesn't fit it (default bg)
+    --pixel-shape <s>   Lit pixel shape: square, circle (default square)
+    --gpu-scale         Upload display at native resolution and let the GPU scale it
+    --persist-frames <n>
+                        Keep cleared pixels visible for n more frames (default 0)
+    --smooth-flicker    Show every pixel lit at any point of a 60Hz tick
+    --grayscale         Sprites hold one intensity byte per pixel, rendered as shades
+    --draw-mode <mode>  How Dxyn combines sprites with display: xor, or (default xor)
+    --vip-display       Writes to 0xF00-0xFFF draw on display like COSMAC VIP
+    --show-grid         Outline unlit cells to show pixel grid
+    --show-stats        Graph recent frame times in bottom left corner
+    --show-wrap         Ghost wrapped sprite pixels at their unwrapped position
+    --opcode-histogram  Chart instructions executed per second by opcode group
+    --profile <name>    Quirks profile: chip8, schip, xochip
+    --auto-profile      Apply profile suggested by scanning ROM opcodes
+    --quirk <q[=off]>   Enable or disable single quirk on top of profile, repeatable
+    --stack-depth <n>   Maximum number of nested calls (default 16)
+    --ram-size <n>      Memory size in bytes, 4096 to 65536 for XO-CHIP (default 4096)
+    --keymap <file>     Keypad and control key bindings
+    --sticky-keys       Pressing a keypad key toggles it instead of holding it
+    --key-debounce-ms <n>
+                        Register key changes only once stable for n ms (default 0)
+    --controller-db <f> Extra SDL game controller mappings (gamecontrollerdb.txt)
+    --list-keys         Print active key bindings and exit, no ROM needed
+    --input-script <f>  Drive keypad from timed events file instead of keyboard
+    --beep-wav <file>   Loop WAV sample as beep instead of the sine tone
+    --beep-ramp-ms <n>  Beep fade in and out time in milliseconds (default 5)
+    --dt-tick           Click whenever delay timer decrements
+    --pan <p>           Beep stereo position from -1.0(left) to 1.0(right) (default 0.0)
+    --seed <n>          Random number generator seed (default random)
+    --record-replay <f> Record settings, seed and input to replay file
+    --play-replay <f>   Play back recorded replay file
+    --start-delay <n>   Frames to wait after loading ROM before running it (default 0)
+    --splash            Show project name for at least a second before ROM starts
+    --fast-boot         Run without window until ROM first draws, then open it
+    --threaded          Run VM on its own thread, window only renders and reads input
+    --headless          Run without window and audio
+    --frames <n>        Number of frames to run in headless mode
+    --max-time <secs>   Stop headless run after wall clock time, combinable with --frames
+    --dump-state-json   Print final state as JSON after headless run
+    --exit-from <src>   Exit headless run with value of reg:VX or mem:ADDR as code
+    --export-svg <file> Write display as SVG on exit
+    --frame-dump <dir>  Write every rendered frame as numbered PNG into dir
+    --frame-dump-changed
+                        Only dump frames where the display was drawn to
+    --save-state-b64    Print memory, registers and display as base64 text on exit
+    --load-state-b64 <s>
+                        Start from state printed by --save-state-b64
+    --ascii-frames <n>  Print display as text every n frames
+    --collision-stats   Report Dxyn collisions after headless run
+    --ipf <n>           Instructions per 60Hz frame (default 9)
+    --accurate-timing   Budget instructions per frame by COSMAC VIP cycle costs
+    --max-speed         Run unthrottled, report instructions per second on exit
+    --max-draws-per-frame <n>
+                        End frame after n Dxyn and report it, caps runaway drawing
+    --expect-frame <f>  Highlight pixels differing from ASCII reference frame
+    --log-draws         Print every Dxyn with coordinates and collision
+    --detect-smc        Print when execution enters memory written by the ROM
+    --detect-uninit     Print when Fx65 or Dxyn read memory never loaded or written
+    --break-on-collision
+                        Pause right after a Dxyn sets VF
+    --trace <file>      Write PC, opcode and registers of every instruction to file
+    --trace-compare <f> Stop at first instruction differing from a --trace file
+    --trace-first <n>   Print trace of first n instructions, then run quietly
+    --strict-keys       Stop when Ex9E/ExA1 test key above F instead of masking it
+    --strict-alignment  Stop when an instruction is fetched from an odd address
+    --warn-code-overwrite
+                        Print when the ROM first writes to each address of itself
+    --validate          Report opcodes which can't be decoded before running
+    --xrefs             Label jump and call targets in disasm output
+    --lenient-decode    Run 5xyN and 9xyN with nonzero N as 5xy0 and 9xy0
+    --step-on-input     Advance one frame only when a keypad key goes down or up
+    --debug             Read debugger commands from stdin
+
+Arguments in CHIP8_ARGS, separated by whitespace, are read before the command line, which overrides them.";
+
+// Environment variable holding extra arguments
+const ENV_ARGS: &str = "CHIP8_ARGS";
+
+/*
+ * Where --exit-from takes the exit code of a headless run from: reg:VX or mem:ADDR, both hex.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitFrom {
+    Register(usize),
+    Memory(u16),
+}
+impl ExitFrom {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid --exit-from {}, expected reg:V0-VF or mem:<hex address>", spec);
+        if let Some(reg) = spec.strip_prefix("reg:") {
+            let index = reg.strip_prefix('V').or_else(|| reg.strip_prefix('v')).ok_or_else(invalid)?;
+            usize::from_str_radix(index, 16).ok().filter(|i| index.len() == 1 && *i < 16).map(ExitFrom::Register).ok_or_else(invalid)
+        } else if let Some(addr) = spec.strip_prefix("mem:") {
+            parse_addr(addr).map(ExitFrom::Memory).map_err(|_| invalid())
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subcommand {
+    Run,
+    Disasm,
+    Validate,
+    Bench,
+    Info,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub command: Subcommand,
+    pub rom: String,
+    pub scale: u32,
+    pub theme: String,
+    pub bg_color: Option<String>,
+    pub border_color: Option<String>,
+    pub pixel_shape: String,
+    pub gpu_scale: bool,
+    pub persist_frames: u8,
+    pub smooth_flicker: bool,
+    pub grayscale: bool,
+    pub draw_mode: String,
+    pub vip_display: bool,
+    pub show_grid: bool,
+    pub show_wrap: bool,
+    pub show_stats: bool,
+    pub opcode_histogram: bool,
+    pub profile: Option<String>,
+    pub auto_profile: bool,
+    pub quirks: Vec<String>,
+    pub stack_depth: usize,
+    pub ram_size: usize,
+    pub keymap: Option<String>,
+    pub sticky_keys: bool,
+    pub key_debounce_ms: u64,
+    pub controller_db: Option<String>,
+    pub list_keys: bool,
+    pub input_script: Option<String>,
+    pub beep_wav: Option<String>,
+    pub beep_ramp_ms: u64,
+    pub pan: f32,
+    pub dt_tick: bool,
+    pub seed: Option<u64>,
+    pub record_replay: Option<String>,
+    pub play_replay: Option<String>,
+    pub start_delay: u64,
+    pub splash: bool,
+    pub fast_boot: bool,
+    pub threaded: bool,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub max_time: Option<f64>,
+    pub dump_state_json: bool,
+    pub exit_from: Option<ExitFrom>,
+    pub export_svg: Option<String>,
+    pub frame_dump: Option<String>,
+    pub frame_dump_changed: bool,
+    pub save_state_b64: bool,
+    pub load_state_b64: Option<String>,
+    pub ascii_frames: u64,
+    pub collision_stats: bool,
+    pub instructions_per_frame: usize,
+    pub accurate_timing: bool,
+    pub max_speed: bool,
+    pub max_draws_per_frame: Option<u32>,
+    pub bench_opcodes: bool,
+    pub expect_frame: Option<String>,
+    pub log_draws: bool,
+    pub detect_smc: bool,
+    pub detect_uninit: bool,
+    pub break_on_collision: bool,
+    pub trace: Option<String>,
+    pub trace_compare: Option<String>,
+    pub trace_first: u64,
+    pub strict_keys: bool,
+    pub strict_alignment: bool,
+    pub warn_code_overwrite: bool,
+    pub validate: bool,
+    pub xrefs: bool,
+    pub lenient_decode: bool,
+    pub step_on_input: bool,
+    pub debug: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            command: Subcommand::Run,
+            rom: String::new(),
+            scale: 10,
+            theme: String::from("white"),
+            bg_color: None,
+            border_color: None,
+            pixel_shape: String::from("square"),
+            gpu_scale: false,
+            persist_frames: 0,
+            smooth_flicker: false,
+            grayscale: false,
+            draw_mode: String::from("xor"),
+            vip_display: false,
+            show_grid: false,
+            show_wrap: false,
+            show_stats: false,
+            opcode_histogram: false,
+            profile: None,
+            auto_profile: false,
+            quirks: Vec::new(),
+            stack_depth: crate::STACK_SIZE,
+            ram_size: crate::RAM_SIZE,
+            keymap: None,
+            sticky_keys: false,
+            key_debounce_ms: 0,
+            controller_db: None,
+            list_keys: false,
+            input_script: None,
+            beep_wav: None,
+            beep_ramp_ms: 5,
+            pan: 0.0,
+            dt_tick: false,
+            seed: None,
+            record_replay: None,
+            play_replay: None,
+            start_delay: 0,
+            splash: false,
+            fast_boot: false,
+            threaded: false,
+            headless: false,
+            frames: None,
+            max_time: None,
+            dump_state_json: false,
+            exit_from: None,
+            export_svg: None,
+            frame_dump: None,
+            frame_dump_changed: false,
+            save_state_b64: false,
+            load_state_b64: None,
+            ascii_frames: 0,
+            collision_stats: false,
+            instructions_per_frame: crate::INSTRUCTIONS_PER_FRAME,
+            accurate_timing: false,
+            max_speed: false,
+            max_draws_per_frame: None,
+            bench_opcodes: false,
+            expect_frame: None,
+            log_draws: false,
+            detect_smc: false,
+            detect_uninit: false,
+            break_on_collision: false,
+            trace: None,
+            trace_compare: None,
+            trace_first: 0,
+            strict_keys: false,
+            strict_alignment: false,
+            warn_code_overwrite: false,
+            validate: false,
+            xrefs: false,
+            lenient_decode: false,
+            step_on_input: false,
+            debug: false,
+        }
+    }
+}
+
+impl Options {
+    /*
+     * Parses arguments, skipping program name.
+     */
+    pub fn parse(args: Vec<String>) -> Result<Self, String> {
+        Options::default().with_args(args)
+    }
+
+    /*
+     * Per-ROM settings from sidecar file next to the ROM, e.g. game.ch8.toml:
+     *
+     *   ipf = 15
+     *   profile = "schip"
+     *   theme = "amber"
+     *   [quirks]
+     *   display_wait = off
+     *
+     * Top level keys are ipf, profile, theme, pixel_shape, scale and accurate_timing. Quirks are applied on
+     * top of the profile. Command line arguments are applied over the result with with_args.
+     */
+    pub fn from_sidecar(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let mut opts = Options::default();
+
+        for entry in config::parse(&text).map_err(|e| format!("{}: {}", path, e))? {
+            let invalid = || format!("{}:{}: invalid value for {}: {}", path, entry.line, entry.key, entry.value);
+            match (entry.section.as_str(), entry.key.as_str()) {
+                ("", "ipf") => opts.instructions_per_frame = entry.value.parse().map_err(|_| invalid())?,
+                ("", "scale") => opts.scale = entry.value.parse().map_err(|_| invalid())?,
+                ("", "profile") => opts.profile = Some(entry.value.clone()),
+                ("", "theme") => opts.theme = entry.value.clone(),
+                ("", "pixel_shape") => opts.pixel_shape = entry.value.clone(),
+                ("", "accurate_timing") => opts.accurate_timing = parse_switch(&entry.value).ok_or_else(invalid)?,
+                ("quirks", name) => opts.quirks.push(format!("{}={}", name, entry.value)),
+                (section, key) => return Err(format!("{}:{}: unknown setting {}", path, entry.line,
+                    if section.is_empty() { key.to_string() } else { format!("{}.{}", section, key) })),
+            }
+        }
+        Ok(opts)
+    }
+
+    /*
+     * Applies CHIP8_ARGS and then arguments, skipping program name, on top of current options. Later values
+     * win, so command line overrides the environment; a ROM path or command on the command line replaces one
+     * from the environment.
+     */
+    pub fn with_args(self, args: Vec<String>) -> Result<Self, String> {
+        let mut opts = self;
+        if let Ok(extra) = env::var(ENV_ARGS) {
+            opts = opts.apply(extra.split_whitespace().map(String::from).collect())?;
+        }
+        opts.apply(args.into_iter().skip(1).collect())?.validate()
+    }
+
+    fn apply(self, args: Vec<String>) -> Result<Self, String> {
+        let mut opts = self;
+        let mut rom = None;
+        let mut args = args.into_iter().peekable();
+
+        // Subcommand can only be the first argument
+        let command = match args.peek().map(|a| a.as_str()) {
+            Some("run") => Some(Subcommand::Run),
+            Some("disasm") => Some(Subcommand::Disasm),
+            Some("validate") => Some(Subcommand::Validate),
+            Some("bench") => Some(Subcommand::Bench),
+            Some("info") => Some(Subcommand::Info),
+            _ => None,
+        };
+        if let Some(command) = command {
+            opts.command = command;
+            args.next();
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scale" => opts.scale = parse_num(&arg, args.next())?,
+                "--theme" => opts.theme = value(&arg, args.next())?,
+                "--bg-color" => opts.bg_color = Some(value(&arg, args.next())?),
+                "--border-color" => opts.border_color = Some(value(&arg, args.next())?),
+                "--pixel-shape" => opts.pixel_shape = value(&arg, args.next())?,
+                "--gpu-scale" => opts.gpu_scale = true,
+                "--persist-frames" => opts.persist_frames = parse_num(&arg, args.next())?,
+                "--smooth-flicker" => opts.smooth_flicker = true,
+                "--grayscale" => opts.grayscale = true,
+                "--draw-mode" => opts.draw_mode = value(&arg, args.next())?,
+                "--vip-display" => opts.vip_display = true,
+                "--show-grid" => opts.show_grid = true,
+                "--show-wrap" => opts.show_wrap = true,
+                "--show-stats" => opts.show_stats = true,
+                "--opcode-histogram" => opts.opcode_histogram = true,
+                "--profile" => opts.profile = Some(value(&arg, args.next())?),
+                "--auto-profile" => opts.auto_profile = true,
+                "--quirk" => opts.quirks.push(value(&arg, args.next())?),
+                "--stack-depth" => opts.stack_depth = parse_num(&arg, args.next())?,
+                "--ram-size" => opts.ram_size = parse_num(&arg, args.next())?,
+                "--keymap" => opts.keymap = Some(value(&arg, args.next())?),
+                "--sticky-keys" => opts.sticky_keys = true,
+                "--key-debounce-ms" => opts.key_debounce_ms = parse_num(&arg, args.next())?,
+                "--controller-db" => opts.controller_db = Some(value(&arg, args.next())?),
+                "--list-keys" => opts.list_keys = true,
+                "--input-script" => opts.input_script = Some(value(&arg, args.next())?),
+                "--seed" => opts.seed = Some(parse_num(&arg, args.next())?),
+                "--record-replay" => opts.record_replay = Some(value(&arg, args.next())?),
+                "--play-replay" => opts.play_replay = Some(value(&arg, args.next())?),
+                "--start-delay" => opts.start_delay = parse_num(&arg, args.next())?,
+                "--splash" => opts.splash = true,
+                "--fast-boot" => opts.fast_boot = true,
+                "--threaded" => opts.threaded = true,
+                "--headless" => opts.headless = true,
+                "--beep-wav" => opts.beep_wav = Some(value(&arg, args.next())?),
+                "--beep-ramp-ms" => opts.beep_ramp_ms = parse_num(&arg, args.next())?,
+                "--dt-tick" => opts.dt_tick = true,
+                "--pan" => opts.pan = parse_num(&arg, args.next())?,
+                "--frames" => opts.frames = Some(parse_num(&arg, args.next())?),
+                "--max-time" => opts.max_time = Some(parse_num(&arg, args.next())?),
+                "--dump-state-json" => opts.dump_state_json = true,
+                "--exit-from" => opts.exit_from = Some(ExitFrom::parse(&value(&arg, args.next())?)?),
+                "--export-svg" => opts.export_svg = Some(value(&arg, args.next())?),
+                "--frame-dump" => opts.frame_dump = Some(value(&arg, args.next())?),
+                "--frame-dump-changed" => opts.frame_dump_changed = true,
+                "--save-state-b64" => opts.save_state_b64 = true,
+                "--load-state-b64" => opts.load_state_b64 = Some(value(&arg, args.next())?),
+                "--ascii-frames" => opts.ascii_frames = parse_num(&arg, args.next())?,
+                "--collision-stats" => opts.collision_stats = true,
+                "--ipf" => opts.instructions_per_frame = parse_num(&arg, args.next())?,
+                "--accurate-timing" => opts.accurate_timing = true,
+                "--max-speed" => opts.max_speed = true,
+                "--max-draws-per-frame" => opts.max_draws_per_frame = Some(parse_num(&arg, args.next())?),
+                "--opcodes" => opts.bench_opcodes = true,
+                "--expect-frame" => opts.expect_frame = Some(value(&arg, args.next())?),
+                "--log-draws" => opts.log_draws = true,
+                "--detect-smc" => opts.detect_smc = true,
+                "--detect-uninit" => opts.detect_uninit = true,
+                "--break-on-collision" => opts.break_on_collision = true,
+                "--trace" => opts.trace = Some(value(&arg, args.next())?),
+                "--trace-compare" => opts.trace_compare = Some(value(&arg, args.next())?),
+                "--trace-first" => opts.trace_first = parse_num(&arg, args.next())?,
+                "--strict-keys" => opts.strict_keys = true,
+                "--strict-alignment" => opts.strict_alignment = true,
+                "--warn-code-overwrite" => opts.warn_code_overwrite = true,
+                "--validate" => opts.validate = true,
+                "--xrefs" => opts.xrefs = true,
+                "--lenient-decode" => opts.lenient_decode = true,
+                "--step-on-input" => opts.step_on_input = true,
+                "--debug" => opts.debug = true,
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}\n\n{}", arg, USAGE)),
+                _ => {
+                    if rom.is_some() { return Err(String::from(USAGE)); }
+                    rom = Some(arg);
+                }
+            }
+        }
+
+        if let Some(rom) = rom { opts.rom = rom; }
+        Ok(opts)
+    }
+
+    fn validate(self) -> Result<Self, String> {
+        let opts = self;
+        // Diagnostics which exit right away don't need a ROM, window without one waits for a dropped file
+        let bench_opcodes = opts.bench_opcodes && opts.command == Subcommand::Bench;
+        if opts.rom.is_empty() && !(opts.list_keys || bench_opcodes || (!opts.headless && opts.command == Subcommand::Run)) {
+            return Err(String::from(USAGE));
+        }
+        if opts.max_time.is_some_and(|secs| !(secs > 0.0 && secs.is_finite())) {
+            return Err(String::from("--max-time must be a positive number of seconds"));
+        }
+        if opts.scale == 0 { return Err(String::from("--scale must be at least 1")); }
+        if opts.max_draws_per_frame == Some(0) { return Err(String::from("--max-draws-per-frame must be at least 1")); }
+        if opts.instructions_per_frame == 0 { return Err(String::from("--ipf must be at least 1")); }
+        if !(-1.0..=1.0).contains(&opts.pan) { return Err(String::from("--pan must be between -1.0 and 1.0")); }
+        Ok(opts)
+    }
+}
+
+fn value(flag: &str, val: Option<String>) -> Result<String, String> {
+    val.ok_or_else(|| format!("Missing value for {}", flag))
+}
+
+fn parse_switch(val: &str) -> Option<bool> {
+    match val {
+        "on" | "true" => Some(true),
+        "off" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_num<T: std::str::FromStr>(flag: &str, val: Option<String>) -> Result<T, String> {
+    let val = value(flag, val)?;
+    val.parse().map_err(|_| format!("Invalid value for {}: {}", flag, val))
+}
@@ -0,0 +1,165 @@
+/*
+ * QUIRKS
+ * Behaviours which differ between CHIP-8 interpreters. Default matches what this emulator always did, except for
+ * wrap_i_addressing which is on like on most hardware.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vy and store result in Vx, instead of shifting Vx in place.
+    pub shift_uses_vy: bool,
+    // Fx55/Fx65 leave I incremented by x + 1.
+    pub load_store_increments_i: bool,
+    // Bnnn jumps to xnn + Vx, instead of nnn + V0.
+    pub jump_uses_vx: bool,
+    // Dxyn waits for display interrupt, no more instructions run in the frame after a draw.
+    pub display_wait: bool,
+    // Fx0A stores the key once it's released, like the COSMAC VIP, instead of as soon as it's pressed.
+    pub fx0a_on_release: bool,
+    // Pixels of a sprite wrapped around the display edge don't set VF on collision.
+    pub no_wrapped_collision: bool,
+    // Sprite pixels past the right or bottom display edge aren't drawn instead of wrapping around.
+    pub clip_sprites: bool,
+    // Dxyn, Fx33, Fx55 and Fx65 addresses past the end of memory wrap to its start instead of stopping.
+    pub wrap_i_addressing: bool,
+    // 8xy1/8xy2/8xy3 set VF to 0 after the logic operation, like the COSMAC VIP.
+    pub logic_resets_vf: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            display_wait: false,
+            fx0a_on_release: false,
+            no_wrapped_collision: false,
+            clip_sprites: false,
+            wrap_i_addressing: true,
+            logic_resets_vf: false,
+        }
+    }
+}
+
+impl Quirks {
+    /*
+     * Sets quirk by name, used by --quirk name[=on|off].
+     */
+    pub fn set(&mut self, name: &str, on: bool) -> Result<(), String> {
+        let quirk = match name {
+            "shift_uses_vy" => &mut self.shift_uses_vy,
+            "load_store_increments_i" => &mut self.load_store_increments_i,
+            "jump_uses_vx" => &mut self.jump_uses_vx,
+            "display_wait" => &mut self.display_wait,
+            "fx0a_on_release" => &mut self.fx0a_on_release,
+            "no_wrapped_collision" => &mut self.no_wrapped_collision,
+            "clip_sprites" => &mut self.clip_sprites,
+            "wrap_i_addressing" => &mut self.wrap_i_addressing,
+            "logic_resets_vf" => &mut self.logic_resets_vf,
+            _ => return Err(format!("Unknown quirk {}", name)),
+        };
+        *quirk = on;
+        Ok(())
+    }
+
+    /*
+     * Parses "name", "name=on" or "name=off".
+     */
+    pub fn apply(&mut self, spec: &str) -> Result<(), String> {
+        let mut parts = spec.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let on = match parts.next() {
+            None | Some("on") | Some("true") => true,
+            Some("off") | Some("false") => false,
+            Some(other) => return Err(format!("Invalid value for quirk {}: {}", name, other)),
+        };
+        self.set(name, on)
+    }
+}
+
+/*
+ * Built-in profiles.
+ */
+pub const PROFILES: [(&str, Quirks); 3] = [
+    ("chip8",  Quirks { shift_uses_vy: true,  load_store_increments_i: true,  jump_uses_vx: false, display_wait: true,  fx0a_on_release: true,  no_wrapped_collision: false, clip_sprites: true,  wrap_i_addressing: true, logic_resets_vf: true }),
+    ("schip",  Quirks { shift_uses_vy: false, load_store_increments_i: false, jump_uses_vx: true,  display_wait: false, fx0a_on_release: false, no_wrapped_collision: false, clip_sprites: true,  wrap_i_addressing: true, logic_resets_vf: false }),
+    ("xochip", Quirks { shift_uses_vy: true,  load_store_increments_i: true,  jump_uses_vx: false, display_wait: false, fx0a_on_release: false, no_wrapped_collision: false, clip_sprites: false, wrap_i_addressing: true, logic_resets_vf: false }),
+];
+
+pub fn profile(name: &str) -> Option<Quirks> {
+    PROFILES.iter().find(|(n, _)| *n == name).map(|(_, q)| *q)
+}
+
+/*
+ * KNOWN ROMS
+ * FNV-1a hash of ROM bytes -> (title, recommended profile).
+ */
+const KNOWN_ROMS: [(u64, &str, &str); 6] = [
+    (0x06d44afd0b3773b2, "Airplane",       "chip8"),
+    (0x2671acb470b32f3c, "Breakout",       "chip8"),
+    (0x6f57b2223d3f1584, "Particle Demo",  "chip8"),
+    (0x618a84f06fe32861, "Space Invaders", "schip"),
+    (0x3e2c2d43b296b74c, "Tank",           "chip8"),
+    (0x8d8a02fa3a2ed293, "UFO",            "chip8"),
+];
+
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/*
+ * Returns warning if ROM is known and the selected profile isn't the recommended one. Without a profile, or with
+ * single --quirk changes on top of it, the user's choice is trusted.
+ */
+pub fn mismatch_warning(bytes: &[u8], selected: Option<&str>) -> Option<String> {
+    let hash = fnv1a(bytes);
+    let (_, title, name) = KNOWN_ROMS.iter().find(|(h, _, _)| *h == hash)?;
+    match selected {
+        Some(selected) if selected != *name => Some(format!("{} expects {} quirks; run with --profile {}.", title, name, name)),
+        _ => None,
+    }
+}
+
+/*
+ * VARIANT DETECTION
+ * Scans aligned opcodes for instructions which only exist in SCHIP or XO-CHIP.
+ * Data mixed with code can produce false positives, so it's only a hint.
+ */
+pub struct Detection {
+    pub profile: &'static str,
+    // Found opcodes with address of first occurrence
+    pub evidence: Vec<(&'static str, u16)>,
+}
+
+pub fn detect_variant(bytes: &[u8], entry: u16) -> Option<Detection> {
+    let mut schip: Vec<(&'static str, u16)> = Vec::new();
+    let mut xochip: Vec<(&'static str, u16)> = Vec::new();
+
+    for (i, pair) in bytes.chunks(2).enumerate() {
+        if pair.len() < 2 { break; }
+        let op = ((pair[0] as u16) << 8) + pair[1] as u16;
+        let addr = entry + (i * 2) as u16;
+        let (found, list) = match (op, op >> 12, op & 0x00FF) {
+            (0x00FF, _, _)   => ("00FF", &mut schip),
+            (0x00FE, _, _)   => ("00FE", &mut schip),
+            (0xF002, _, _)   => ("F002", &mut xochip),
+            (0xF000, _, _)   => ("F000", &mut xochip),
+            (_, 0xD, _) if op & 0x000F == 0 => ("Dxy0", &mut schip),
+            (_, 0xF, 0x30)   => ("Fx30", &mut schip),
+            (_, 0xF, 0x75)   => ("Fx75", &mut schip),
+            (_, 0xF, 0x85)   => ("Fx85", &mut schip),
+            (_, 0xF, 0x01)   => ("Fn01", &mut xochip),
+            _ => continue,
+        };
+        if !list.iter().any(|(name, _)| *name == found) { list.push((found, addr)); }
+    }
+
+    if !xochip.is_empty() {
+        xochip.extend(schip);
+        Some(Detection { profile: "xochip", evidence: xochip })
+    } else if !schip.is_empty() {
+        Some(Detection { profile: "schip", evidence: schip })
+    } else {
+        None
+    }
+}
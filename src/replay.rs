@@ -0,0 +1,185 @@
+/*
+ * REPLAY
+ * Session recorded with --record-replay, played back deterministically with --play-replay:
+ *
+ *   # chip8 replay
+ *   rom = 8d8a02fa3a2ed293
+ *   seed = 42
+ *   ipf = 9
+ *   accurate_timing = off
+ *   max_draws = off
+ *   ram_size = 4096
+ *   stack_depth = 16
+ *   lenient_decode = off
+ *   draw_mode = xor
+ *   frames = 600
+ *   [quirks]
+ *   shift_uses_vy = on
+ *   ...
+ *   [input]
+ *   30 5 down
+ *   45 5 up
+ *
+ * rom is FNV-1a hash of the ROM bytes. Everything below [input] is an input script, see script.rs. Settings missing
+ * from older replays keep their defaults.
+ */
+use std::fs;
+use std::io::Write;
+
+use crate::config;
+use crate::quirks::{self, Quirks};
+use crate::script::InputScript;
+use crate::{DrawMode, State};
+
+const INPUT_HEADER: &str = "[input]";
+
+// Order of values written by Recorder::save
+const QUIRK_NAMES: [&str; 9] = ["shift_uses_vy", "load_store_increments_i", "jump_uses_vx", "display_wait", "fx0a_on_release",
+    "no_wrapped_collision", "clip_sprites", "wrap_i_addressing", "logic_resets_vf"];
+
+/*
+ * Settings which affect execution, everything playback needs besides input.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub rom_hash: u64,
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub instructions_per_frame: usize,
+    pub accurate_timing: bool,
+    pub max_draws_per_frame: Option<u32>,
+    pub ram_size: usize,
+    pub stack_depth: usize,
+    pub lenient_decode: bool,
+    pub draw_mode: DrawMode,
+}
+
+impl Settings {
+    fn new() -> Self {
+        Settings { rom_hash: 0, seed: 0, quirks: Quirks::default(), instructions_per_frame: 0, accurate_timing: false,
+            max_draws_per_frame: None, ram_size: crate::RAM_SIZE, stack_depth: crate::STACK_SIZE, lenient_decode: false,
+            draw_mode: DrawMode::Xor }
+    }
+}
+
+pub struct Replay {
+    pub settings: Settings,
+    // Number of recorded frames
+    pub frames: u64,
+    pub script: InputScript,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read replay {}: {}", path, e))?;
+        let split = text.find(INPUT_HEADER).ok_or_else(|| format!("{}: missing {} section", path, INPUT_HEADER))?;
+        let (header, input) = (&text[..split], &text[split + INPUT_HEADER.len()..]);
+
+        let mut settings = Settings::new();
+        let mut frames = 0;
+        for entry in config::parse(header).map_err(|e| format!("{}: {}", path, e))? {
+            let invalid = || format!("{}:{}: invalid value for {}: {}", path, entry.line, entry.key, entry.value);
+            match (entry.section.as_str(), entry.key.as_str()) {
+                ("", "rom") => settings.rom_hash = u64::from_str_radix(&entry.value, 16).map_err(|_| invalid())?,
+                ("", "seed") => settings.seed = entry.value.parse().map_err(|_| invalid())?,
+                ("", "ipf") => settings.instructions_per_frame = entry.value.parse().map_err(|_| invalid())?,
+                ("", "accurate_timing") => settings.accurate_timing = entry.value == "on",
+                ("", "max_draws") if entry.value == "off" => settings.max_draws_per_frame = None,
+                ("", "max_draws") => settings.max_draws_per_frame = Some(entry.value.parse().map_err(|_| invalid())?),
+                ("", "ram_size") => settings.ram_size = entry.value.parse().map_err(|_| invalid())?,
+                ("", "stack_depth") => settings.stack_depth = entry.value.parse().map_err(|_| invalid())?,
+                ("", "lenient_decode") => settings.lenient_decode = entry.value == "on",
+                ("", "draw_mode") => settings.draw_mode = DrawMode::parse(&entry.value).map_err(|_| invalid())?,
+                ("", "frames") => frames = entry.value.parse().map_err(|_| invalid())?,
+                ("quirks", name) => settings.quirks.apply(&format!("{}={}", name, entry.value))
+                    .map_err(|e| format!("{}:{}: {}", path, entry.line, e))?,
+                (_, key) => return Err(format!("{}:{}: unknown setting {}", path, entry.line, key)),
+            }
+        }
+        if settings.instructions_per_frame == 0 { return Err(format!("{}: ipf must be at least 1", path)); }
+        if settings.max_draws_per_frame == Some(0) { return Err(format!("{}: max_draws must be at least 1", path)); }
+        if settings.ram_size < crate::RAM_SIZE || settings.ram_size > crate::MAX_RAM_SIZE {
+            return Err(format!("{}: ram_size must be between {} and {}", path, crate::RAM_SIZE, crate::MAX_RAM_SIZE));
+        }
+        if settings.stack_depth == 0 || settings.stack_depth > crate::MAX_STACK_SIZE {
+            return Err(format!("{}: stack_depth must be between 1 and {}", path, crate::MAX_STACK_SIZE));
+        }
+
+        // Line numbers of input errors are relative to [input]
+        let script = InputScript::parse(input).map_err(|e| format!("{} [input]: {}", path, e))?;
+        Ok(Replay { settings: settings, frames: frames, script: script })
+    }
+
+    /*
+     * Warning if replay was recorded with a different ROM.
+     */
+    pub fn rom_warning(&self, rom: &[u8]) -> Option<String> {
+        if quirks::fnv1a(rom) == self.settings.rom_hash { return None; }
+        Some(format!("replay was recorded with a different ROM ({:016x})", self.settings.rom_hash))
+    }
+}
+
+/*
+ * Collects keypad changes per frame while running.
+ */
+pub struct Recorder {
+    path: String,
+    settings: Settings,
+    events: Vec<(u64, usize, bool)>,
+    keys: [bool; 16],
+    frames: u64,
+}
+
+impl Recorder {
+    pub fn new(path: &str, settings: Settings) -> Self {
+        Recorder { path: path.to_string(), settings: settings, events: Vec::new(), keys: [false; 16], frames: 0 }
+    }
+
+    /*
+     * Called once keypad is updated, before frame is executed.
+     */
+    pub fn record(&mut self, frame: u64, state: &State) {
+        for key in 0..16 {
+            let down = state.is_key_down(key);
+            if down != self.keys[key] {
+                self.events.push((frame, key, down));
+                self.keys[key] = down;
+            }
+        }
+        self.frames = frame + 1;
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let write = || -> std::io::Result<()> {
+            let mut file = fs::File::create(&self.path)?;
+            let s = &self.settings;
+            writeln!(file, "# chip8 replay")?;
+            writeln!(file, "rom = {:016x}", s.rom_hash)?;
+            writeln!(file, "seed = {}", s.seed)?;
+            writeln!(file, "ipf = {}", s.instructions_per_frame)?;
+            writeln!(file, "accurate_timing = {}", if s.accurate_timing { "on" } else { "off" })?;
+            match s.max_draws_per_frame {
+                Some(draws) => writeln!(file, "max_draws = {}", draws)?,
+                None => writeln!(file, "max_draws = off")?,
+            }
+            writeln!(file, "ram_size = {}", s.ram_size)?;
+            writeln!(file, "stack_depth = {}", s.stack_depth)?;
+            writeln!(file, "lenient_decode = {}", if s.lenient_decode { "on" } else { "off" })?;
+            writeln!(file, "draw_mode = {}", s.draw_mode.name())?;
+            writeln!(file, "frames = {}", self.frames)?;
+            writeln!(file, "[quirks]")?;
+            let values = [s.quirks.shift_uses_vy, s.quirks.load_store_increments_i, s.quirks.jump_uses_vx,
+                s.quirks.display_wait, s.quirks.fx0a_on_release, s.quirks.no_wrapped_collision, s.quirks.clip_sprites,
+                s.quirks.wrap_i_addressing, s.quirks.logic_resets_vf];
+            for (name, on) in QUIRK_NAMES.iter().zip(values.iter()) {
+                writeln!(file, "{} = {}", name, if *on { "on" } else { "off" })?;
+            }
+            writeln!(file, "{}", INPUT_HEADER)?;
+            for (frame, key, down) in &self.events {
+                writeln!(file, "{} {:X} {}", frame, key, if *down { "down" } else { "up" })?;
+            }
+            Ok(())
+        };
+        write().map_err(|e| format!("Unable to write replay {}: {}", self.path, e))
+    }
+}
@@ -0,0 +1,168 @@
+/*
+ * SAVE STATES
+ * Snapshot of memory, stack, registers and display as a single line of base64 text, short enough to paste into a
+ * chat. Bytes before compression, numbers big endian:
+ *
+ *   "C8" version  PC(2) I(2) SP DT ST V0-VF  Fx0A register or FF
+ *   stack depth, return addresses(2 each)
+ *   display width, height, pixels packed 8 per byte
+ *   memory size(4), memory
+ *
+ * Memory is mostly zeros, so it's run length encoded like PackBits: a header byte n below 128 is followed by n + 1
+ * literal bytes, above 128 by one byte repeated 257 - n times.
+ */
+
+const MAGIC: &[u8] = b"C8";
+const VERSION: u8 = 1;
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub struct Snapshot {
+    pub pc: u16,
+    pub i: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+    pub v: [u8; 16],
+    pub awaiting_input: u8,
+    pub stack: Vec<u16>,
+    pub width: u8,
+    pub height: u8,
+    pub display: Vec<u8>,
+    pub mem: Vec<u8>,
+}
+
+pub fn encode(snapshot: &Snapshot) -> String {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&snapshot.pc.to_be_bytes());
+    bytes.extend_from_slice(&snapshot.i.to_be_bytes());
+    bytes.extend_from_slice(&[snapshot.sp, snapshot.dt, snapshot.st]);
+    bytes.extend_from_slice(&snapshot.v);
+    bytes.push(snapshot.awaiting_input);
+    bytes.push(snapshot.stack.len() as u8);
+    for addr in &snapshot.stack { bytes.extend_from_slice(&addr.to_be_bytes()); }
+    bytes.extend_from_slice(&[snapshot.width, snapshot.height]);
+    bytes.extend_from_slice(&snapshot.display);
+    bytes.extend_from_slice(&(snapshot.mem.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&snapshot.mem);
+    to_base64(&compress(&bytes))
+}
+
+pub fn decode(text: &str) -> Result<Snapshot, String> {
+    let bytes = decompress(&from_base64(text.trim())?)?;
+    let mut reader = Reader { bytes: &bytes, pos: 0 };
+    if reader.take(MAGIC.len())? != MAGIC { return Err(String::from("Not a save state")); }
+    let version = reader.byte()?;
+    if version != VERSION { return Err(format!("Unsupported save state version {}", version)); }
+
+    let (pc, i) = (reader.word()?, reader.word()?);
+    let (sp, dt, st) = (reader.byte()?, reader.byte()?, reader.byte()?);
+    let mut v = [0u8; 16];
+    v.copy_from_slice(reader.take(16)?);
+    let awaiting_input = reader.byte()?;
+    let depth = reader.byte()?;
+    let stack = (0..depth).map(|_| reader.word()).collect::<Result<Vec<u16>, String>>()?;
+    let (width, height) = (reader.byte()?, reader.byte()?);
+    let display = reader.take((width as usize * height as usize).div_ceil(8))?.to_vec();
+    let size = u32::from_be_bytes([reader.byte()?, reader.byte()?, reader.byte()?, reader.byte()?]) as usize;
+    let mem = reader.take(size)?.to_vec();
+    if reader.pos != bytes.len() { return Err(String::from("Save state has trailing data")); }
+
+    Ok(Snapshot {pc: pc, i: i, sp: sp, dt: dt, st: st, v: v, awaiting_input: awaiting_input, stack: stack,
+        width: width, height: height, display: display, mem: mem})
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos + len;
+        if end > self.bytes.len() { return Err(String::from("Save state is truncated")); }
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+}
+
+fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut literal: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let run = bytes[pos..].iter().take(128).take_while(|b| **b == bytes[pos]).count();
+        if run >= 2 {
+            if !literal.is_empty() { flush_literal(&mut out, &mut literal); }
+            out.push((257 - run) as u8);
+            out.push(bytes[pos]);
+            pos += run;
+        } else {
+            literal.push(bytes[pos]);
+            if literal.len() == 128 { flush_literal(&mut out, &mut literal); }
+            pos += 1;
+        }
+    }
+    if !literal.is_empty() { flush_literal(&mut out, &mut literal); }
+    out
+}
+
+fn flush_literal(out: &mut Vec<u8>, literal: &mut Vec<u8>) {
+    out.push(literal.len() as u8 - 1);
+    out.append(literal);
+}
+
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut reader = Reader { bytes: bytes, pos: 0 };
+    while reader.pos < bytes.len() {
+        let header = reader.byte()? as usize;
+        match header {
+            0..=127 => out.extend_from_slice(reader.take(header + 1)?),
+            128 => return Err(String::from("Save state is corrupted")),
+            _ => { let byte = reader.byte()?; out.extend(std::iter::repeat_n(byte, 257 - header)); },
+        }
+    }
+    Ok(out)
+}
+
+fn to_base64(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn from_base64(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::new();
+    let (mut group, mut bits) = (0u32, 0);
+    for c in text.chars() {
+        let value = BASE64.iter().position(|b| *b as char == c)
+            .ok_or_else(|| format!("Invalid character {:?} in save state", c))?;
+        group = group << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((group >> bits) as u8);
+            group &= (1 << bits) - 1;
+        }
+    }
+    Ok(out)
+}
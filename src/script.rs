@@ -0,0 +1,69 @@
+/*
+ * INPUT SCRIPT
+ * Timed keypad events replacing live input. One event per line:
+ *
+ *   # frame key state
+ *   30 5 down
+ *   45 5 up
+ *
+ * frame - 60Hz frame number counted from start (decimal)
+ * key   - keypad value 0-F (hex)
+ * state - down or up
+ *
+ * Events have to be sorted by frame. A key stays down until its up event. Events are applied one by one, so
+ * down and up on the same frame still complete Fx0A.
+ */
+use std::fs;
+
+use crate::State;
+
+pub struct InputScript {
+    events: Vec<(u64, usize, bool)>,
+    next: usize,
+}
+
+impl InputScript {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read input script {}: {}", path, e))?;
+        InputScript::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut events: Vec<(u64, usize, bool)> = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 { return Err(format!("line {}: expected <frame> <key> <down|up>", i + 1)); }
+
+            let frame: u64 = fields[0].parse().map_err(|_| format!("line {}: invalid frame {}", i + 1, fields[0]))?;
+            let key = usize::from_str_radix(fields[1], 16).ok().filter(|k| *k < 16)
+                .ok_or_else(|| format!("line {}: key must be 0-F", i + 1))?;
+            let down = match fields[2] {
+                "down" => true,
+                "up" => false,
+                other => return Err(format!("line {}: expected down or up, got {}", i + 1, other)),
+            };
+            if events.last().is_some_and(|(last, _, _)| *last > frame) {
+                return Err(format!("line {}: events are not sorted by frame", i + 1));
+            }
+            events.push((frame, key, down));
+        }
+        Ok(InputScript { events: events, next: 0 })
+    }
+
+    /*
+     * Applies events scheduled up to given frame. Returns same as map_keyboard, lowest pressed key or 0xFF.
+     */
+    pub fn apply(&mut self, frame: u64, state: &mut State) -> u8 {
+        while let Some((at, key, down)) = self.events.get(self.next) {
+            if *at > frame { break; }
+            // Keys were validated when parsing
+            if *down { state.press(*key).unwrap(); } else { state.release(*key).unwrap(); }
+            self.next += 1;
+        }
+        state.pressed_key()
+    }
+}
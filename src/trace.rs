@@ -0,0 +1,84 @@
+/*
+ * TRACE
+ * One line per executed instruction with the state before it runs, written by --trace:
+ *
+ *   0200 6A02 I=000 SP=0 DT=00 ST=00 V=00000000000000000000000000000000
+ *
+ * --trace-compare checks every line against a trace recorded earlier and stops at the first one which differs,
+ * so a refactor breaking emulation shows where it went wrong. --trace-first prints the first lines to stdout
+ * numbered from 1, to follow a ROM's setup.
+ */
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use crate::State;
+
+pub fn format(state: &State) -> String {
+    let reg = &state.reg;
+    let v: String = reg.V.iter().map(|b| format!("{:02X}", b)).collect();
+    // Fetch past end of memory stops right after the line is written
+    let op = state.peek_opcode().map_or(String::from("????"), |op| format!("{:04X}", op));
+    format!("{:04X} {} I={:03X} SP={} DT={:02X} ST={:02X} V={}", reg.PC, op, reg.I, reg.SP, reg.DT, reg.ST, v)
+}
+
+#[derive(Debug)]
+pub struct Tracer {
+    out: Option<io::BufWriter<fs::File>>,
+    reference: Option<io::Lines<io::BufReader<fs::File>>>,
+    // Instructions printed to stdout from start
+    first: u64,
+    // Instructions traced so far
+    count: u64,
+}
+
+impl Tracer {
+    pub fn new(out: Option<&String>, reference: Option<&String>, first: u64) -> Result<Self, String> {
+        let out = match out {
+            Some(path) => Some(io::BufWriter::new(fs::File::create(path).map_err(|e| format!("Unable to create {}: {}", path, e))?)),
+            None => None,
+        };
+        let reference = match reference {
+            Some(path) => Some(io::BufReader::new(fs::File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?).lines()),
+            None => None,
+        };
+        Ok(Tracer {out: out, reference: reference, first: first, count: 0})
+    }
+
+    /*
+     * Traces instruction at PC, panics if it diverges from the reference trace.
+     */
+    pub fn record(&mut self, state: &State) {
+        let line = format(state);
+        self.count += 1;
+        if self.count <= self.first {
+            println!("{:>6} {}", self.count, line);
+            if self.count == self.first { println!("Traced first {} instructions, running quietly", self.first); }
+        }
+        if let Some(ref mut out) = self.out {
+            writeln!(out, "{}", line).unwrap_or_else(|e| panic!("Unable to write trace: {}", e));
+        }
+        let expected = match self.reference {
+            Some(ref mut reference) => reference.next(),
+            None => return,
+        };
+        match expected {
+            Some(Ok(ref expected)) if *expected == line => {},
+            Some(Ok(expected)) => panic!("Trace diverges at instruction {}\n  expected: {}\n  actual:   {}", self.count, expected, line),
+            Some(Err(e)) => panic!("Unable to read reference trace: {}", e),
+            None => {
+                println!("Reference trace ends after {} instructions, no divergence", self.count - 1);
+                self.reference = None;
+            },
+        }
+    }
+
+    /*
+     * Writes out buffered lines, called on shutdown.
+     */
+    pub fn finish(&mut self) -> Result<(), String> {
+        match self.out {
+            Some(ref mut out) => out.flush().map_err(|e| format!("Unable to write trace: {}", e)),
+            None => Ok(()),
+        }
+    }
+}